@@ -1,17 +1,41 @@
 use std::collections::HashMap;
 
-use crate::{InstructionPart, Lexem, LexemType, Token, TYPES};
+use crate::{big_endian_bytes, little_endian_bytes, InstructionPart, InstructionsLexer, Lexem, LexemType, Token};
 
 #[derive(Debug)]
 pub struct CodeGen<'a>{
     tokens: &'a[Token],
-    instruction_set: &'a HashMap<&'static str,Vec<InstructionPart>>,
+    forms: &'a HashMap<usize, usize>,
+    instruction_lexer: &'a InstructionsLexer,
     pub bytes: Vec<u8>
 }
 
 pub fn get_value_from_number_token<'a>(lexem: &Lexem) -> usize{
+    let val = get_signed_value_from_number_token(lexem);
+
+    if val < 0{
+        println!("{}:{}:{} Expected unsigned number got {}", lexem.filename, lexem.row, lexem.col, val);
+        std::process::exit(1);
+    }
+
+    val as usize
+}
+
+pub fn get_signed_value_from_number_token(lexem: &Lexem) -> i64{
     match lexem.ttype{
-        LexemType::Number { radix } => {usize::from_str_radix(&lexem.value, radix as u32).unwrap()}
+        // literals above i64::MAX keep their 64 bit pattern (0xFFFFFFFFFFFFFFFF is -1)
+        LexemType::Number { radix } => {
+            match i64::from_str_radix(&lexem.value, radix as u32){
+                Ok(a) => a,
+                Err(_) => match u64::from_str_radix(&lexem.value, radix as u32){
+                    Ok(a) => a as i64,
+                    Err(_) => {
+                        println!("{}:{}:{} Number {} doesn't fit in 64 bits", lexem.filename, lexem.row, lexem.col, lexem.value);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
         _ => {
             println!("{}:{}:{} Expected number got {}", lexem.filename, lexem.row, lexem.col,lexem.ttype);
             std::process::exit(1);
@@ -20,173 +44,133 @@ pub fn get_value_from_number_token<'a>(lexem: &Lexem) -> usize{
 }
 
 impl CodeGen<'_>{
-    pub fn new<'a>(tokens: &'a[Token], instruction_set: &'a HashMap<&'static str,Vec<InstructionPart>>) -> CodeGen<'a>{
+    pub fn new<'a>(tokens: &'a[Token], forms: &'a HashMap<usize, usize>, instruction_lexer: &'a InstructionsLexer) -> CodeGen<'a>{
         CodeGen{
             tokens,
-            instruction_set,
+            forms,
+            instruction_lexer,
             bytes: Vec::new()
         }
     }
 
-    pub fn str_to_bytes(self: &Self, str: &String) -> Vec<u8>{
+    // bits are padded to multiple of `pad_to` and split into addresses (bytes or words),
+    // every address is written as whole bytes
+    pub fn str_to_bytes(self: &Self, str: &String, pad_to: usize, little_endian: bool) -> Vec<u8>{
         if str.len() == 0{
             return vec![];
         }
 
-        let ret: usize = usize::from_str_radix(str, 2).unwrap();
-        if str.len() <= 8{
-            return vec![ret as u8];
-        }
+        let unit = self.instruction_lexer.unit_size();
+        let padded_len = str.len().div_ceil(pad_to) * pad_to;
+        let str = "0".repeat(padded_len - str.len()) + str.as_str();
 
-        if str.len() <= 16{
-            return (ret as u16).to_be_bytes().to_vec();
+        let unit_bytes = unit.div_ceil(8);
+
+        let mut units: Vec<u64> = Vec::new();
+
+        for i in (0..str.len()).step_by(unit){
+            units.push(u64::from_str_radix(&str[i..(i+unit).min(str.len())], 2).unwrap());
         }
 
-        if str.len() <= 32{
-            return (ret as u32).to_be_bytes().to_vec();
+        if little_endian{
+            units.reverse();
         }
 
-        if str.len() <= 64{
-            return (ret as u64).to_be_bytes().to_vec();
+        let mut bytes: Vec<u8> = Vec::new();
+
+        for val in units{
+            if little_endian{
+                bytes.append(&mut little_endian_bytes(val, unit_bytes));
+            }else{
+                bytes.append(&mut big_endian_bytes(val, unit_bytes));
+            }
         }
 
-        eprintln!("too big");
-        std::process::exit(1);
+        bytes
     }
 
+    fn gen_data(self: &mut Self, name: &Lexem, args: &[Lexem], bits: usize){
+        if args.len() == 0{
+            println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
+            std::process::exit(1);
+        }
 
-    fn gen_token(self: &mut Self, token: &Token){
-        match token{
-            Token::Instruction { name, args } => {
-                match name.value.as_str(){
-                    "org" => {
-                        println!("Org: Error in parser");
+        let pad_to = self.instruction_lexer.unit_size();
+        let little_endian = self.instruction_lexer.data_little_endian;
+        let mask = u64::MAX >> (64 - bits);
+
+        for arg in args{
+            match arg.ttype{
+                LexemType::Number { .. } => {
+                    let val = get_signed_value_from_number_token(arg);
+
+                    // both signed and unsigned values are accepted
+                    if bits < 64 && (val < -(1i64 << (bits - 1)) || val >= (1i64 << bits)){
+                        println!("{}:{}:{} Number {} doesn't fit in {} bits ({}..={})", arg.filename, arg.row, arg.col, val, bits, -(1i64 << (bits - 1)), (1i64 << bits) - 1);
                         std::process::exit(1);
                     }
 
-                    "db" => {
-                        if args.len() == 0{
-                            println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                            std::process::exit(1);
-                        }
-                        
-                        for arg in args{
-                            match arg.ttype{
-                                LexemType::Number { radix } => {
-                                    let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFF ) as u8).to_be_bytes();
-                                    for b in b{
-                                        self.bytes.push(b);
-                                    }
-                                },
-                                LexemType::String => {
-                                    for ch in arg.value.chars(){
-                                        self.bytes.push(ch as u8);
-                                    }
-                                },
-                                _ => {
-                                    println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    std::process::exit(1);
-                                }
-                            }
-                        }
+                    let val = (val as u64) & mask;
+                    let mut b = self.str_to_bytes(&format!("{:0width$b}", val, width = bits), pad_to, little_endian);
+                    self.bytes.append(&mut b);
+                },
+                LexemType::String => {
+                    for ch in arg.value.chars(){
+                        let val = (ch as u64) & mask;
+                        let mut b = self.str_to_bytes(&format!("{:0width$b}", val, width = bits), pad_to, little_endian);
+                        self.bytes.append(&mut b);
                     }
+                },
+                _ => {
+                    println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
 
-                    "dw" => {
-                        if args.len() == 0{
-                            println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                            std::process::exit(1);
-                        }
+    // parser already checked offset and length against file size
+    fn gen_incbin(self: &mut Self, args: &[Lexem]){
+        let file = &args[0];
+        let offset = get_value_from_number_token(&args[1]);
+        let length = get_value_from_number_token(&args[2]);
 
-                        for arg in args{
-                            match arg.ttype{
-                                LexemType::Number { radix } => {
-                                    let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFF ) as u16).to_be_bytes();
-                                    for b in b{
-                                        self.bytes.push(b);
-                                    }
-                                },
-                                LexemType::String => {
-                                    for ch in arg.value.chars(){
-                                        self.bytes.append(&mut (ch as u16).to_be_bytes().to_vec());
-                                    }
-                                },
-                                _ => {
-                                    println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    std::process::exit(1);
-                                }
-                            }
-                        }
+        let bytes = match std::fs::read(&file.value){
+            Ok(a) => a,
+            Err(e) => {
+                println!("{}:{}:{} Couldn't read {}: {}", file.filename, file.row, file.col, file.value, e);
+                std::process::exit(1);
+            }
+        };
 
-                    }
+        let unit_bytes = self.instruction_lexer.unit_size().div_ceil(8);
 
-                    "dd" => {
-                        if args.len() == 0{
-                            println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                            std::process::exit(1);
-                        }
+        self.bytes.extend_from_slice(&bytes[offset..offset+length]);
+        self.bytes.append(&mut vec![0; self.instruction_lexer.byte_units(length) * unit_bytes - length]);
+    }
 
-                        for arg in args{
-                            match arg.ttype{
-                                LexemType::Number { radix } => {
-                                    let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFFFFFF ) as u32).to_be_bytes();
-                                    for b in b{
-                                        self.bytes.push(b);
-                                    }
-                                },
-                                LexemType::String => {
-                                    for ch in arg.value.chars(){
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(ch as u8);
-                                    }
-                                },
-                                _ => {
-                                    println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    std::process::exit(1);
-                                }
-                            }
-                        }
+    fn gen_token(self: &mut Self, token: &Token, form: Option<usize>){
+        match token{
+            Token::Instruction { name, args } => {
+                match name.value.as_str(){
+                    "org" => {
+                        println!("Org: Error in parser");
+                        std::process::exit(1);
                     }
 
-                    "dq" => {
-                        if args.len() == 0{
-                            println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                            std::process::exit(1);
-                        }
+                    "db" => self.gen_data(name, args, 8),
 
-                        for arg in args{
-                            match arg.ttype{
-                                LexemType::Number { radix } => {
-                                    let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFFFFFFFFFFFFFF ) as u32).to_be_bytes();
-                                    for b in b{
-                                        self.bytes.push(b);
-                                    }
-                                },
-                                LexemType::String => {
-                                    for ch in arg.value.chars(){
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(0);
-                                        self.bytes.push(ch as u8);
-                                    }
-                                },
-                                _ => {
-                                    println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    std::process::exit(1);
-                                }
-                            }
-                        }
+                    "dw" => self.gen_data(name, args, 16),
 
-                    }
+                    "dd" => self.gen_data(name, args, 32),
+
+                    "dq" => self.gen_data(name, args, 64),
+
+                    ".incbin" => self.gen_incbin(args),
 
                     _ => {
-                        let instruction = match self.instruction_set.get(&name.value.as_str()){
-                            Some(a) => a,
+                        let instruction = match self.instruction_lexer.instructions.get(&name.value){
+                            Some(_) => self.instruction_lexer.select_instruction(name, args, form.unwrap_or(0)),
                             None => {
                                 // match self.pseudo_instructions.get(&name.value){
                                 //     Some(a) => {
@@ -208,17 +192,18 @@ impl CodeGen<'_>{
                                 println!("{}:{}:{} Unknown instruction {}", name.filename, name.row, name.col, name.value);
                                 std::process::exit(1);
                             }
-                        }.as_slice();
+                        };
 
-                        let mut args = args.clone();
+                        let mut args: Vec<Lexem> = instruction.match_pattern(args).unwrap().iter().map(|a| args[*a].clone()).collect();
+                        let operand_args = args.clone();
 
-                        let mut bits_str = String::new();
+                        let mut operand_bits: Vec<String> = Vec::new();
                         
-                        for part in instruction{
+                        for part in instruction.operands.iter(){
+                            let mut bits_str = String::new();
+
                             match part{
-                                InstructionPart::Const { val } => {
-                                    bits_str+=val;
-                                },
+                                InstructionPart::Const { .. } | InstructionPart::Slice { .. } => unreachable!(),
 
                                 InstructionPart::Type { val, size } => {
                                     if args.len() == 0{
@@ -232,7 +217,7 @@ impl CodeGen<'_>{
                                     }
                                     let type_val = val.to_uppercase();
 
-                                    let type_hashmap = match TYPES.get(&type_val){
+                                    let type_hashmap = match self.instruction_lexer.types.get(&type_val){
                                         Some(a) => a,
                                         None => {
                                             println!("{}:{}:{} following type {} doesn't exist", arg.filename, arg.row, arg.col, type_val);
@@ -249,10 +234,15 @@ impl CodeGen<'_>{
                                         }
                                     };
 
-                                    let val = format!("{:b}", val);
+                                    let val_str = format!("{:b}", val);
 
-                                    bits_str+="0".repeat(*size - val.len()).as_str();
-                                    bits_str+=val.as_str();
+                                    if val_str.len() > *size{
+                                        println!("{}:{}:{} {} {} = {} doesn't fit in {} bit field", arg.filename, arg.row, arg.col, type_val, arg.value, val, size);
+                                        std::process::exit(1);
+                                    }
+
+                                    bits_str+="0".repeat(*size - val_str.len()).as_str();
+                                    bits_str+=val_str.as_str();
                                 }
                                 
                                 InstructionPart::Imm { size } => {
@@ -282,9 +272,42 @@ impl CodeGen<'_>{
                                     bits_str += val.as_str();
                                 }
 
+                                InstructionPart::SImm { size } | InstructionPart::Rel { size } => {
+                                    if args.len() == 0{
+                                        println!("{}:{}:{} Expected Immediate", name.filename, name.row, name.col+name.value.len());
+                                        std::process::exit(1);
+                                    }
+                                    let arg = args.remove(0);
+
+                                    if arg.ttype == LexemType::Ident{
+                                        println!("{}:{}:{} Use of undeclared label {}", arg.filename, arg.row, arg.col, arg.value);
+                                        std::process::exit(1);
+                                    }
+
+                                    let val = get_signed_value_from_number_token(&arg);
+
+                                    let min = -(1i64 << (*size - 1));
+                                    let max = (1i64 << (*size - 1)) - 1;
+
+                                    if val < min || val > max{
+                                        if matches!(part, InstructionPart::Rel { .. }){
+                                            println!("{}:{}:{} Target out of range, offset {} doesn't fit in {} bits ({}..={})", arg.filename, arg.row, arg.col, val, size, min, max);
+                                        }else{
+                                            println!("{}:{}:{} Number {} doesn't fit in signed {} bit field ({}..={})", arg.filename, arg.row, arg.col, val, size, min, max);
+                                        }
+                                        std::process::exit(1);
+                                    }
+
+                                    // two's complement in field width
+                                    let val = format!("{:b}", (val as u64) & (u64::MAX >> (64 - *size)));
+
+                                    bits_str+="0".repeat(*size - val.len()).as_str();
+                                    bits_str += val.as_str();
+                                }
+
                                 InstructionPart::Extra { size } => {
                                     if args.len() == 0{
-                                        bits_str += "0".repeat(*size).as_str();
+                                        operand_bits.push("0".repeat(*size));
                                         continue;
                                     }
                                     
@@ -305,9 +328,37 @@ impl CodeGen<'_>{
                                 }
                                 
                             }
+
+                            operand_bits.push(bits_str);
+                        }
+
+                        // bits left out of sliced operand can't be dropped silently
+                        for (i, bits) in operand_bits.iter().enumerate(){
+                            if let Some(bit) = instruction.uncovered_bits(i).into_iter().find(|a| bits.as_bytes()[bits.len()-1-a] == b'1'){
+                                let arg = operand_args.get(i).unwrap_or(name);
+                                println!("{}:{}:{} Bit {} of {} is set but {} doesn't encode it", arg.filename, arg.row, arg.col, bit, arg.value, name.value);
+                                std::process::exit(1);
+                            }
+                        }
+
+                        let mut bits_str = String::new();
+
+                        for part in instruction.parts.iter(){
+                            match part{
+                                InstructionPart::Const { val } => {
+                                    bits_str+=val;
+                                }
+
+                                InstructionPart::Slice { operand, hi, lo } => {
+                                    let operand = &operand_bits[*operand];
+                                    bits_str += &operand[operand.len()-1-hi..=operand.len()-1-lo];
+                                }
+
+                                _ => unreachable!()
+                            }
                         }
 
-                        let mut that_bytes = self.str_to_bytes(&bits_str);
+                        let mut that_bytes = self.str_to_bytes(&bits_str, self.instruction_lexer.instruction_bits(instruction), self.instruction_lexer.little_endian);
                         self.bytes.append(&mut that_bytes);
                     }
                 }
@@ -322,14 +373,12 @@ impl CodeGen<'_>{
         }
     }
 
-    pub fn gen_block<'a>(self: &mut Self, in_tokens: &'a [Token]){
-        for token in in_tokens.iter(){
-            self.gen_token(token);
-        }
-    }
-
     pub fn gen(self: &mut Self){
-        self.gen_block(self.tokens);
+        let tokens = self.tokens;
+
+        for (i, token) in tokens.iter().enumerate(){
+            self.gen_token(token, self.forms.get(&i).copied());
+        }
     }
 }
 
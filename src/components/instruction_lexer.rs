@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::{INSTRUCTIONS, TYPES};
+use crate::{get_signed_value_from_number_token, Isa, IsaEntry, Lexem, LexemType, PUNCTUATION_LEXEMS};
 
 #[derive(Debug, Clone)]
 pub enum InstructionPart{
@@ -10,6 +10,12 @@ pub enum InstructionPart{
     Imm{
         size: usize,
     },
+    SImm{
+        size: usize,
+    },
+    Rel{
+        size: usize,
+    },
     Type{
         val: String,
         size: usize,
@@ -17,12 +23,166 @@ pub enum InstructionPart{
     Extra{
         size: usize
     },
+    Slice{
+        operand: usize,
+        hi: usize,
+        lo: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatternPart{
+    Literal{
+        val: String
+    },
+    Operand,
+}
+
+// operands hold kinds of arguments in source order (Imm, SImm, Rel, Type, Extra)
+// parts describe bit layout from msb to lsb (Const and Slice of operand)
+// pattern is how operands are written in source (`[%]`, `[seg:%]`)
+#[derive(Debug, Clone)]
+pub struct Instruction{
+    pub operands: Vec<InstructionPart>,
+    pub parts: Vec<InstructionPart>,
+    pub pattern: Vec<PatternPart>,
+    pub filename: String,
+    pub row: usize,
+    pub col: usize
+}
+
+impl std::fmt::Display for InstructionPart{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self{
+            InstructionPart::Const { val } => {write!(f, "{}", val)},
+            InstructionPart::Imm { size } => {write!(f, "IMM{}", size)},
+            InstructionPart::SImm { size } => {write!(f, "SIMM{}", size)},
+            InstructionPart::Rel { size } => {write!(f, "REL{}", size)},
+            InstructionPart::Type { val, size } => {write!(f, "{}{}", val, size)},
+            InstructionPart::Extra { size } => {write!(f, "E{}", size)},
+            InstructionPart::Slice { operand, hi, lo } => {write!(f, "@{}[{}:{}]", operand + 1, hi, lo)},
+        }
+    }
+}
+
+impl Instruction{
+    // size in bits
+    pub fn size(self: &Self) -> usize{
+        let mut instruction_size: usize = 0;
+        for instruction_part in self.parts.iter(){
+            match instruction_part {
+                InstructionPart::Const { val } => {
+                    instruction_size += val.len();
+                }
+
+                InstructionPart::Slice { operand: _, hi, lo } => {
+                    instruction_size += hi - lo + 1;
+                }
+
+                _ => unreachable!()
+            }
+        }
+
+        instruction_size
+    }
+
+    // bits of operand that no slice puts into encoding, `{IMM[7:4]}` leaves out bits 3..0
+    pub fn uncovered_bits(self: &Self, operand: usize) -> Vec<usize>{
+        let size = match &self.operands[operand]{
+            InstructionPart::Imm { size } | InstructionPart::SImm { size } | InstructionPart::Rel { size } | InstructionPart::Type { size, .. } | InstructionPart::Extra { size } => *size,
+            InstructionPart::Const { .. } | InstructionPart::Slice { .. } => unreachable!()
+        };
+
+        (0..size).filter(|bit| !self.parts.iter().any(|a| matches!(a, InstructionPart::Slice { operand: o, hi, lo } if *o == operand && lo <= bit && bit <= hi))).collect()
+    }
+
+    pub fn signature(self: &Self, name: &str) -> String{
+        let mut operands = self.operands.iter();
+        let mut signature = name.to_string() + " ";
+
+        for part in self.pattern.iter(){
+            match part{
+                PatternPart::Literal { val } if val == "," => signature += ", ",
+                PatternPart::Literal { val } => signature += val,
+                PatternPart::Operand => signature += operands.next().unwrap().to_string().as_str()
+            }
+        }
+
+        signature.trim_end().to_string()
+    }
+
+    // positions of operands in args, trailing Extra operands can be left out
+    pub fn match_pattern(self: &Self, args: &[Lexem]) -> Option<Vec<usize>>{
+        let mut positions: Vec<usize> = Vec::new();
+        let mut args = args.iter().enumerate().peekable();
+
+        for part in self.pattern.iter(){
+            match part{
+                PatternPart::Literal { val } if val == "," => {}
+                PatternPart::Literal { val } => {
+                    let (_, arg) = args.next()?;
+                    if arg.value.to_lowercase() != *val || !matches!(arg.ttype, LexemType::Single | LexemType::Operator | LexemType::Ident){
+                        return None;
+                    }
+                }
+                PatternPart::Operand => {
+                    match args.peek(){
+                        Some((_, arg)) if !is_punctuation_arg(arg) => {
+                            positions.push(args.next().unwrap().0);
+                        }
+                        Some(_) => return None,
+                        None => {
+                            if !matches!(self.operands[positions.len()], InstructionPart::Extra { .. }){
+                                return None;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // like before templates, extra operands of plain instructions are ignored
+        if args.next().is_some() && self.pattern.iter().any(|a| matches!(a, PatternPart::Literal { val } if val != ",")){
+            return None;
+        }
+
+        if positions.len() < self.operands.len() && self.operands[positions.len()..].iter().any(|a| !matches!(a, InstructionPart::Extra { .. })){
+            return None;
+        }
+
+        Some(positions)
+    }
+
+    fn relative_operands(self: &Self) -> Vec<bool>{
+        self.operands.iter().map(|a| matches!(a, InstructionPart::Rel { .. })).collect()
+    }
+}
+
+fn is_punctuation_arg(arg: &Lexem) -> bool{
+    (arg.ttype == LexemType::Single && PUNCTUATION_LEXEMS.contains(&arg.value.as_str())) || arg.ttype == LexemType::Operator
+}
+
+struct Field{
+    ttype: String,
+    size: Option<usize>,
+    slice: Option<(usize, usize)>,
+    operand: Option<usize>,
+    cursor: usize
 }
 
 #[derive(Debug)]
 pub struct InstructionsLexer{
     cursor: usize,
-    pub instructions: HashMap<&'static str,Vec<InstructionPart>>
+    filename: String,
+    row: usize,
+    col: usize,
+    pub instructions: HashMap<String,Vec<Instruction>>,
+    pub types: HashMap<String, HashMap<String, usize>>,
+    pub word_size: usize,
+    pub word_addressed: bool,
+    pub instruction_width: Option<usize>,
+    pub little_endian: bool,
+    pub data_little_endian: bool
 }
 
 
@@ -31,62 +191,174 @@ impl InstructionsLexer{
     pub fn new() -> InstructionsLexer{
         InstructionsLexer{
             cursor: 0,
-            instructions: HashMap::new()
+            filename: String::new(),
+            row: 0,
+            col: 0,
+            instructions: HashMap::new(),
+            types: HashMap::new(),
+            word_size: 8,
+            word_addressed: false,
+            instruction_width: None,
+            little_endian: false,
+            data_little_endian: false
+        }
+    }
+
+    // bits in one address (byte or word)
+    pub fn unit_size(self: &Self) -> usize{
+        if self.word_addressed{
+            self.word_size
+        }else{
+            8
+        }
+    }
+
+    // instructions always take whole words or exactly declared width
+    pub fn instruction_bits(self: &Self, instruction: &Instruction) -> usize{
+        match self.instruction_width{
+            Some(a) => a,
+            None => instruction.size().div_ceil(self.word_size) * self.word_size
         }
     }
 
-    fn peek(self: &mut Self, str: &'static str) -> Option<char>{
+    pub fn instruction_units(self: &Self, instruction: &Instruction) -> usize{
+        self.instruction_bits(instruction) / self.unit_size()
+    }
+
+    pub fn data_units(self: &Self, bits: usize) -> usize{
+        bits.div_ceil(self.unit_size())
+    }
+
+    // raw bytes are packed into addresses, last one is padded
+    pub fn byte_units(self: &Self, bytes: usize) -> usize{
+        bytes.div_ceil(self.unit_size().div_ceil(8))
+    }
+
+    fn peek(self: &mut Self, str: &str) -> Option<char>{
         str.chars().nth(self.cursor).clone()
     }
 
-    fn chop(self: &mut Self, str: &'static str) -> char{
+    fn chop(self: &mut Self, str: &str) -> char{
         let x = self.peek(str).unwrap();
         self.cursor += 1;
         x
     }
 
-    fn chop_white_space(self: &mut Self, str: &'static str){
+    fn location(self: &Self) -> String{
+        format!("{}:{}:{}", self.filename, self.row, self.col + self.cursor)
+    }
+
+    fn chop_white_space(self: &mut Self, str: &str){
         while self.cursor < str.len() && self.peek(str).unwrap().is_whitespace(){
             self.chop(str);
         }
     }
 
-    pub fn get_instruction_size(self: &Self, name: &str) -> usize{
-        match self.instructions.get(name){
-            Some(a) => {
-                let mut instruction_size: usize = 0;
-                for instruction_part in a{
-                    match instruction_part {
-                        InstructionPart::Const { val } => {
-                            instruction_size += val.len();
-                        }
-
-                        InstructionPart::Extra { size } => {
-                            instruction_size += size;
-                        }
+    fn is_type_value(self: &Self, arg: &Lexem) -> bool{
+        arg.ttype == LexemType::Ident && self.types.values().any(|a| a.contains_key(arg.value.to_lowercase().as_str()))
+    }
 
-                        InstructionPart::Imm { size } => {
-                            instruction_size += size;
-                        }
-                        InstructionPart::Type { val: _, size } => {
-                            instruction_size += size;
-                        }
-                    }
+    // relative operand holds target, its offset is taken from address of the instruction
+    fn operand_matches(self: &Self, operand: &InstructionPart, arg: &Lexem, check_values: bool, address: i64) -> bool{
+        match operand{
+            InstructionPart::Type { val, size: _ } => {
+                arg.ttype == LexemType::Ident && self.types[val].contains_key(arg.value.to_lowercase().as_str())
+            }
+            InstructionPart::Imm { size } | InstructionPart::Extra { size } => {
+                if self.is_type_value(arg){
+                    return false;
+                }
+                if !check_values || !matches!(arg.ttype, LexemType::Number { .. }){
+                    return true;
+                }
+                let val = get_signed_value_from_number_token(arg);
+                val >= 0 && (*size >= 63 || val < (1i64 << size))
+            }
+            InstructionPart::SImm { size } | InstructionPart::Rel { size } => {
+                if self.is_type_value(arg){
+                    return false;
                 }
+                if !check_values || !matches!(arg.ttype, LexemType::Number { .. }){
+                    return true;
+                }
+                let mut val = get_signed_value_from_number_token(arg);
+                if matches!(operand, InstructionPart::Rel { .. }){
+                    val -= address;
+                }
+                val >= -(1i64 << (size - 1)) && val < (1i64 << (size - 1))
+            }
+            InstructionPart::Const { .. } | InstructionPart::Slice { .. } => unreachable!()
+        }
+    }
 
-                let mut return_size = instruction_size / 8;
+    fn form_matches(self: &Self, instruction: &Instruction, args: &[Lexem], check_values: bool, address: i64) -> bool{
+        match instruction.match_pattern(args){
+            Some(positions) if positions.len() + instruction.pattern.iter().filter(|a| matches!(a, PatternPart::Literal { val } if val != ",")).count() != args.len() => false,
+            Some(positions) => instruction.operands.iter().zip(positions.iter()).all(|(operand, i)| self.operand_matches(operand, &args[*i], check_values, address)),
+            None => false
+        }
+    }
 
-                if instruction_size % 8 != 0{
-                    return_size += 1;
-                }
+    // overloaded mnemonics are picked by operand kinds and values that are already known,
+    // symbols are assumed to fit. Form never gets narrower than the one picked in previous pass (index),
+    // so label addresses only grow and passes end. Returns index of the form
+    pub fn select_form(self: &Self, name: &Lexem, args: &[Lexem], address: usize, previous: Option<usize>) -> usize{
+        let forms = match self.instructions.get(&name.value){
+            Some(a) => a,
+            None => {
+                eprintln!("{}:{}:{} Instruction `{}` doesn't exist", name.filename, name.row, name.col,&name.value);
+                std::process::exit(1);
+            }
+        };
 
-                return_size
+        if forms.len() == 1{
+            if forms[0].match_pattern(args).is_none(){
+                println!("{}:{}:{} Operands of {} don't match, expected:", name.filename, name.row, name.col, name.value);
+                println!("    {}", forms[0].signature(&name.value));
+                std::process::exit(1);
             }
-            None => return usize::MAX
+            return 0;
         }
+
+        let min_size = previous.map_or(0, |a| forms[a].size());
+
+        if let Some(i) = forms.iter().position(|a| a.size() >= min_size && self.form_matches(a, args, true, address as i64)){
+            return i;
+        }
+
+        self.report_no_form(name, args);
     }
 
-    fn chop_ones_zeroes(self: &mut Self, str: &'static str) -> Option<InstructionPart>{
+    fn report_no_form(self: &Self, name: &Lexem, args: &[Lexem]) -> !{
+        if self.instructions[&name.value].iter().any(|a| self.form_matches(a, args, false, 0)){
+            println!("{}:{}:{} Operands of {} don't fit any form, candidates:", name.filename, name.row, name.col, name.value);
+        }else{
+            println!("{}:{}:{} No form of {} matches given operands, candidates:", name.filename, name.row, name.col, name.value);
+        }
+        for form in self.instructions[&name.value].iter(){
+            println!("    {}", form.signature(&name.value));
+        }
+        std::process::exit(1);
+    }
+
+    // once values are known only forms with the same size and relative operands as the selected one are considered
+    pub fn select_instruction(self: &Self, name: &Lexem, args: &[Lexem], selected: usize) -> &Instruction{
+        let forms = &self.instructions[&name.value];
+        let first = &forms[selected];
+
+        if forms.len() == 1{
+            return first;
+        }
+
+        let compatible = forms.iter().find(|a| a.size() == first.size() && a.relative_operands() == first.relative_operands() && self.form_matches(a, args, true, 0));
+
+        match compatible{
+            Some(a) => a,
+            None => self.report_no_form(name, args)
+        }
+    }
+
+    fn chop_ones_zeroes(self: &mut Self, str: &str) -> Option<InstructionPart>{
         
         let mut val = String::new();
 
@@ -109,13 +381,27 @@ impl InstructionsLexer{
         
     }
 
-    fn chop_curly(self: &mut Self,name: &'static str, str: &'static str) -> Option<InstructionPart>{
+    fn chop_number(self: &mut Self, str: &str) -> Option<usize>{
+        let mut number = String::new();
+
+        while self.cursor < str.len() && self.peek(str).unwrap().is_ascii_digit(){
+            number += self.chop(str).to_string().as_str();
+        }
+
+        if number.len() == 0{
+            return None;
+        }
+
+        Some(number.parse::<usize>().unwrap())
+    }
+
+    fn chop_curly(self: &mut Self,name: &str, str: &str) -> Option<Field>{
 
         let initial_cursor = self.cursor;
 
         self.chop_white_space(str);
 
-        if self.peek(str).unwrap() != '{'{
+        if self.cursor >= str.len() || self.peek(str).unwrap() != '{'{
             self.cursor = initial_cursor;
             return None
         }
@@ -126,68 +412,189 @@ impl InstructionsLexer{
 
         self.chop_white_space(str);
 
+        let type_cursor = self.cursor;
+
         while self.cursor < str.len() && self.peek(str).unwrap().is_alphabetic(){
             ttype += self.chop(str).to_string().as_str();
         }
 
         if ttype.len() == 0{
-            println!("Instruction Lexer \"{}\": You need to provide type for types", name);
+            println!("{} Instruction Lexer \"{}\": You need to provide type for types", self.location(), name);
             std::process::exit(1);
         }
 
         self.chop_white_space(str);
 
-        let mut size = String::new();
+        let size = self.chop_number(str);
+
+        self.chop_white_space(str);
+
+        // {IMM[7:4]} - only bits 7 to 4 of the operand
+        let mut slice = None;
+
+        if self.cursor < str.len() && self.peek(str).unwrap() == '['{
+            self.chop(str);
+            self.chop_white_space(str);
+
+            let hi = self.chop_number(str);
+
+            self.chop_white_space(str);
+
+            if self.cursor >= str.len() || self.peek(str).unwrap() != ':'{
+                println!("{} Instruction Lexer \"{}\": expected bit range [hi:lo]", self.location(), name);
+                std::process::exit(1);
+            }
+
+            self.chop(str);
+            self.chop_white_space(str);
+
+            let lo = self.chop_number(str);
+
+            self.chop_white_space(str);
+
+            if self.cursor >= str.len() || self.peek(str).unwrap() != ']' || hi.is_none() || lo.is_none(){
+                println!("{} Instruction Lexer \"{}\": expected bit range [hi:lo]", self.location(), name);
+                std::process::exit(1);
+            }
+
+            self.chop(str);
+            self.chop_white_space(str);
 
-        while self.cursor < str.len() && self.peek(str).unwrap().is_numeric(){
-            size += self.chop(str).to_string().as_str();
+            let (hi, lo) = (hi.unwrap(), lo.unwrap());
+
+            if hi < lo{
+                println!("{} Instruction Lexer \"{}\": bit range [{}:{}] has to go from high to low bit", self.location(), name, hi, lo);
+                std::process::exit(1);
+            }
+
+            slice = Some((hi, lo));
+        }
+
+        // {R4@2} - field belongs to second operand
+        let mut operand = None;
+
+        if self.cursor < str.len() && self.peek(str).unwrap() == '@'{
+            self.chop(str);
+            self.chop_white_space(str);
+
+            operand = match self.chop_number(str){
+                Some(0) | None => {
+                    println!("{} Instruction Lexer \"{}\": expected operand number starting from 1 after @", self.location(), name);
+                    std::process::exit(1);
+                }
+                Some(a) => Some(a - 1)
+            };
+
+            self.chop_white_space(str);
         }
 
-        if size.len() == 0{
-            println!("Instruction Lexer \"{}\": You need to provide size for types", name);
+        if size.is_none() && slice.is_none(){
+            println!("{} Instruction Lexer \"{}\": You need to provide size for types", self.location(), name);
             std::process::exit(1);
         }
 
-        self.chop_white_space(str);
+        if self.cursor >= str.len(){
+            println!("{} Instruction Lexer \"{}\": expected closed curly got end of encoding", self.location(), name);
+            std::process::exit(1);
+        }
+
+        if self.peek(str).unwrap() != '}'{
+            println!("{} Instruction Lexer \"{}\": expected closed curly got {}", self.location(), name, self.peek(str).unwrap());
+            std::process::exit(1);
+        }
+
+        self.chop(str);
 
-        let ch = self.chop(str);
+        let ttype = ttype.to_uppercase();
 
-        if ch != '}'{
-            println!("Instruction Lexer \"{}\": expected closed curly got {}", name, ch);
+        if !["IMM", "SIMM", "REL", "E"].contains(&ttype.as_str()) && !self.types.contains_key(ttype.as_str()){
+            self.cursor = type_cursor;
+            println!("{} Instruction Lexer \"{}\": Unknown type {}", self.location(), name, ttype);
             std::process::exit(1);
         }
 
-        let size = usize::from_str_radix(&size, 10).unwrap();
+        Some(Field { ttype, size, slice, operand, cursor: type_cursor })
+    }
+
+    fn operand_kind(self: &mut Self, name: &str, field: &Field, size: usize) -> InstructionPart{
+        self.cursor = field.cursor;
 
-        match ttype.to_uppercase().as_str(){
-            "IMM" => {
-                return Some(InstructionPart::Imm { size });
+        match field.ttype.as_str(){
+            "IMM" => InstructionPart::Imm { size },
+            "SIMM" => {
+                if size == 0 || size > 64{
+                    println!("{} Instruction Lexer \"{}\": Signed immediate size has to be between 1 and 64", self.location(), name);
+                    std::process::exit(1);
+                }
+                InstructionPart::SImm { size }
             },
-            "E" => {
-                return Some(InstructionPart::Extra { size });
+            "REL" => {
+                if size == 0 || size > 64{
+                    println!("{} Instruction Lexer \"{}\": Relative offset size has to be between 1 and 64", self.location(), name);
+                    std::process::exit(1);
+                }
+                InstructionPart::Rel { size }
+            },
+            "E" => InstructionPart::Extra { size },
+            _ => InstructionPart::Type { val: field.ttype.clone(), size }
+        }
+    }
+
+    fn lex_pattern(self: &mut Self, name: &str, pattern: &str, operand_count: usize) -> Vec<PatternPart>{
+        let mut parts: Vec<PatternPart> = Vec::new();
+
+        if pattern.is_empty(){
+            for i in 0..operand_count{
+                if i != 0{
+                    parts.push(PatternPart::Literal { val: ",".to_string() });
+                }
+                parts.push(PatternPart::Operand);
             }
-            _ => {
+            return parts;
+        }
 
-                if !TYPES.contains_key(ttype.to_uppercase().as_str()){
-                    println!("Instruction Lexer \"{}\": Unknown type {}", name, ttype.to_uppercase());
+        let mut chars = pattern.chars().peekable();
+
+        while let Some(ch) = chars.next(){
+            match ch{
+                '%' => parts.push(PatternPart::Operand),
+                '[' | ']' | ':' | '+' | ',' => parts.push(PatternPart::Literal { val: ch.to_string() }),
+                a if a.is_whitespace() => {}
+                a if a.is_alphanumeric() || a == '.' => {
+                    let mut val = a.to_string();
+                    while chars.peek().is_some_and(|a| a.is_alphanumeric() || *a == '.'){
+                        val += chars.next().unwrap().to_string().as_str();
+                    }
+                    parts.push(PatternPart::Literal { val: val.to_lowercase() });
+                }
+                a => {
+                    println!("{}:{}:{} Instruction Lexer \"{}\": unexpected character \"{}\" in operand template", self.filename, self.row, self.col, name, a);
                     std::process::exit(1);
                 }
-
-                return Some(InstructionPart::Type { val: ttype.to_uppercase().clone(), size });
             }
         }
+
+        let placeholders = parts.iter().filter(|a| **a == PatternPart::Operand).count();
+
+        if placeholders != operand_count{
+            println!("{}:{}:{} Instruction Lexer \"{}\": operand template has {} placeholders but encoding has {} operands", self.filename, self.row, self.col, name, placeholders, operand_count);
+            std::process::exit(1);
+        }
+
+        parts
     }
 
-    fn lex_instruction(self: &mut Self, name: &'static str, instruction: &'static str) -> Vec<InstructionPart>{
+    fn lex_instruction(self: &mut Self, name: &str, instruction: &str) -> Instruction{
         
-        let mut parts: Vec<InstructionPart> = Vec::new();
+        let mut fields: Vec<Field> = Vec::new();
+        let mut layout: Vec<Option<InstructionPart>> = Vec::new();
         
         self.cursor = 0;
         while self.cursor < instruction.len(){
 
             match self.chop_ones_zeroes(instruction){
                 Some(x) => {
-                    parts.push(x);
+                    layout.push(Some(x));
                     continue;
                 }
                 None => {}
@@ -195,28 +602,150 @@ impl InstructionsLexer{
 
             match self.chop_curly(name, instruction){
                 Some(x) => {
-                    parts.push(x);
+                    fields.push(x);
+                    layout.push(None);
                     continue;
                 }
                 None => {}
             }
 
-            println!("instruction_lexer: unknown character: \"{}\"", self.peek(instruction).unwrap());
-            dbg!(parts);
+            if self.cursor >= instruction.len(){
+                break;
+            }
+
+            println!("{} instruction_lexer: unknown character: \"{}\"", self.location(), self.peek(instruction).unwrap());
             std::process::exit(1);
         }
 
+        let explicit = fields.iter().filter(|a| a.operand.is_some()).count();
 
-        parts
-    }
+        if explicit != 0 && explicit != fields.len(){
+            self.cursor = fields.iter().find(|a| a.operand.is_none()).unwrap().cursor;
+            println!("{} Instruction Lexer \"{}\": either all fields or none need operand number", self.location(), name);
+            std::process::exit(1);
+        }
+
+        // without explicit numbers operands go in order of appearance
+        // and sliced fields of the same type are parts of one operand
+        let mut field_operands: Vec<usize> = Vec::new();
+        let mut operand_count = 0;
+
+        for (i, field) in fields.iter().enumerate(){
+            let operand = match field.operand{
+                Some(a) => a,
+                None => {
+                    let previous = fields[..i].iter().zip(field_operands.iter())
+                        .find(|(a, _)| field.slice.is_some() && a.slice.is_some() && a.ttype == field.ttype);
+
+                    match previous{
+                        Some((_, a)) => *a,
+                        None => {
+                            operand_count += 1;
+                            operand_count - 1
+                        }
+                    }
+                }
+            };
 
-    pub fn lex_instructions(self: &mut Self){
-        for (name, instruction) in INSTRUCTIONS.entries(){
-            let name = *name;
-            let instruction = *instruction;
+            field_operands.push(operand);
+        }
+
+        let operand_count = field_operands.iter().map(|a| a + 1).max().unwrap_or(0);
+
+        let mut operands: Vec<InstructionPart> = Vec::new();
+
+        for operand in 0..operand_count{
+            let operand_fields: Vec<&Field> = fields.iter().zip(field_operands.iter())
+                .filter(|(_, a)| **a == operand)
+                .map(|(a, _)| a)
+                .collect();
+
+            if operand_fields.is_empty(){
+                println!("{}:{}:{} Instruction Lexer \"{}\": operand @{} isn't used in encoding", self.filename, self.row, self.col, name, operand + 1);
+                std::process::exit(1);
+            }
+
+            let first = operand_fields[0];
+
+            for field in operand_fields.iter(){
+                if field.ttype != first.ttype{
+                    self.cursor = field.cursor;
+                    println!("{} Instruction Lexer \"{}\": operand @{} is already {} not {}", self.location(), name, operand + 1, first.ttype, field.ttype);
+                    std::process::exit(1);
+                }
+            }
+
+            let sizes: Vec<usize> = operand_fields.iter().filter_map(|a| a.size).collect();
+
+            let size = match sizes.first(){
+                Some(size) => {
+                    if let Some(field) = operand_fields.iter().find(|a| a.size.is_some_and(|a| a != *size)){
+                        self.cursor = field.cursor;
+                        println!("{} Instruction Lexer \"{}\": operand @{} already has size {}", self.location(), name, operand + 1, size);
+                        std::process::exit(1);
+                    }
+                    *size
+                }
+                None => operand_fields.iter().map(|a| a.slice.unwrap().0 + 1).max().unwrap()
+            };
+
+            for field in operand_fields.iter(){
+                if let Some((hi, _)) = field.slice{
+                    if hi >= size{
+                        self.cursor = field.cursor;
+                        println!("{} Instruction Lexer \"{}\": bit {} is outside of {} bit operand", self.location(), name, hi, size);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            operands.push(self.operand_kind(name, first, size));
+        }
+
+        let mut parts: Vec<InstructionPart> = Vec::new();
+        let mut fields = fields.iter().zip(field_operands.iter());
+
+        for part in layout{
+            match part{
+                Some(a) => parts.push(a),
+                None => {
+                    let (field, operand) = fields.next().unwrap();
+                    let (hi, lo) = match field.slice{
+                        Some(a) => a,
+                        None => (field.size.unwrap() - 1, 0)
+                    };
+                    parts.push(InstructionPart::Slice { operand: *operand, hi, lo });
+                }
+            }
+        }
+
+        Instruction { operands, parts, pattern: Vec::new(), filename: self.filename.clone(), row: self.row, col: self.col }
+    }
 
-            let instruction = self.lex_instruction(name, instruction);
-            self.instructions.insert(name, instruction);
+    pub fn lex_instructions(self: &mut Self, isa: &Isa){
+        self.instructions.clear();
+        self.types = isa.types.clone();
+        self.word_size = isa.word_size;
+        self.word_addressed = isa.word_addressed;
+        self.instruction_width = isa.instruction_width;
+        self.little_endian = isa.little_endian;
+        self.data_little_endian = isa.data_little_endian.unwrap_or(isa.little_endian);
+
+        for IsaEntry { name, body, filename, row, col } in isa.instructions.iter(){
+            self.filename = filename.clone();
+            self.row = *row;
+            self.col = *col;
+
+            // `st [seg:%]` - mnemonic followed by operand template
+            let (mnemonic, pattern) = match name.trim().split_once(char::is_whitespace){
+                Some((mnemonic, pattern)) => (mnemonic, pattern.trim()),
+                None => (name.trim(), "")
+            };
+
+            let mut instruction = self.lex_instruction(mnemonic, body);
+
+            instruction.pattern = self.lex_pattern(mnemonic, pattern, instruction.operands.len());
+            self.instructions.entry(mnemonic.to_string()).or_default().push(instruction);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{Lexem, LexemType, INSTRUCTIONS, PSEUDO_INSTRUCTIONS, TYPES};
+
+// ISA description file format:
+//
+// [isa]
+// name = rev2
+// word = 12            bits in memory word, instructions take whole words
+// addressing = word    addresses count words instead of bytes
+// width = 24           every instruction is exactly 24 bits
+// endian = little      order of bytes/words in instructions
+// data_endian = big    order of bytes/words in dw, dd, dq (same as endian by default)
+//
+// [types R]
+// zero = 0
+// acc  = 1
+//
+// [instructions]
+// add  = {R4} 0 010
+// addi = {IMM4} 0 011
+//
+// [pseudo lim imm]
+//     swa zero
+//     addi (imm & 0b00001111)
+//
+// Lines starting with `//` are comments, pseudo instruction bodies run until next section
+
+#[derive(Debug, Clone)]
+pub struct IsaEntry{
+    pub name: String,
+    pub body: String,
+    pub filename: String,
+    pub row: usize,
+    pub col: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct Isa{
+    pub name: String,
+    pub filename: String,
+    pub word_size: usize,
+    pub word_addressed: bool,
+    pub instruction_width: Option<usize>,
+    pub little_endian: bool,
+    pub data_little_endian: Option<bool>,
+    pub types: HashMap<String, HashMap<String, usize>>,
+    pub instructions: Vec<IsaEntry>,
+    pub pseudo_instructions: Vec<IsaEntry>
+}
+
+enum Section{
+    None,
+    Isa,
+    Types(String),
+    Instructions,
+    Pseudo(usize)
+}
+
+fn parse_isa_number(value: &str) -> Option<usize>{
+    let value = value.trim();
+
+    if let Some(hex) = value.strip_prefix("0x"){
+        return usize::from_str_radix(hex, 16).ok();
+    }
+
+    if let Some(bin) = value.strip_prefix("0b"){
+        return usize::from_str_radix(bin, 2).ok();
+    }
+
+    value.parse::<usize>().ok()
+}
+
+impl Isa{
+    pub fn builtin() -> Isa{
+        let filename = "BUILTIN_ISA".to_string();
+
+        let mut types: HashMap<String, HashMap<String, usize>> = HashMap::new();
+
+        for (type_name, values) in TYPES.entries(){
+            let mut type_values: HashMap<String, usize> = HashMap::new();
+            for (name, value) in values.entries(){
+                type_values.insert(name.to_string(), *value);
+            }
+            types.insert(type_name.to_string(), type_values);
+        }
+
+        let mut instructions: Vec<IsaEntry> = Vec::new();
+
+        for (name, encoding) in INSTRUCTIONS.entries(){
+            instructions.push(IsaEntry { name: name.to_string(), body: encoding.to_string(), filename: filename.clone(), row: 1, col: 1 });
+        }
+
+        let mut pseudo_instructions: Vec<IsaEntry> = Vec::new();
+
+        for (name, code) in PSEUDO_INSTRUCTIONS.entries(){
+            pseudo_instructions.push(IsaEntry { name: name.to_string(), body: code.to_string(), filename: filename.clone(), row: 1, col: 1 });
+        }
+
+        Isa { name: "default".to_string(), filename, word_size: 8, word_addressed: false, instruction_width: None, little_endian: false, data_little_endian: None, types, instructions, pseudo_instructions }
+    }
+
+    pub fn is_builtin(self: &Self) -> bool{
+        self.filename == "BUILTIN_ISA"
+    }
+
+    pub fn load(path: &Path) -> Isa{
+        let filename = path.to_str().unwrap().to_string();
+
+        let content = match std::fs::read_to_string(path){
+            Ok(a) => a,
+            Err(e) => {
+                println!("{}: Couldn't read ISA file: {}", filename, e);
+                std::process::exit(1);
+            }
+        };
+
+        Self::parse(&filename, &content)
+    }
+
+    pub fn parse(filename: &str, content: &str) -> Isa{
+        let mut isa = Isa{
+            name: match Path::new(filename).file_stem(){
+                Some(a) => a.to_str().unwrap().to_string(),
+                None => filename.to_string()
+            },
+            filename: filename.to_string(),
+            word_size: 8,
+            word_addressed: false,
+            instruction_width: None,
+            little_endian: false,
+            data_little_endian: None,
+            types: HashMap::new(),
+            instructions: Vec::new(),
+            pseudo_instructions: Vec::new()
+        };
+
+        let mut section = Section::None;
+
+        for (i, line) in content.lines().enumerate(){
+            let row = i + 1;
+            let trimmed = line.trim();
+
+            if trimmed.starts_with('[') {
+                if !trimmed.ends_with(']'){
+                    println!("{}:{}:{} Expected \"]\" at the end of section header", filename, row, line.len()+1);
+                    std::process::exit(1);
+                }
+
+                let header = trimmed[1..trimmed.len()-1].trim();
+                let col = line.find('[').unwrap() + 1;
+
+                let (kind, rest) = match header.split_once(char::is_whitespace){
+                    Some((kind, rest)) => (kind, rest.trim()),
+                    None => (header, "")
+                };
+
+                section = match kind.to_lowercase().as_str(){
+                    "isa" => {
+                        if !rest.is_empty(){
+                            println!("{}:{}:{} Unexpected \"{}\" in isa section header", filename, row, col, rest);
+                            std::process::exit(1);
+                        }
+                        Section::Isa
+                    }
+                    "types" | "type" => {
+                        if rest.is_empty() || rest.contains(char::is_whitespace){
+                            println!("{}:{}:{} Expected single type name in section header", filename, row, col);
+                            std::process::exit(1);
+                        }
+
+                        let type_name = rest.to_uppercase();
+
+                        if ["IMM", "SIMM", "REL", "E"].contains(&type_name.as_str()){
+                            println!("{}:{}:{} Type name {} is reserved", filename, row, col, type_name);
+                            std::process::exit(1);
+                        }
+
+                        if isa.types.contains_key(&type_name){
+                            println!("{}:{}:{} Type {} already defined", filename, row, col, type_name);
+                            std::process::exit(1);
+                        }
+
+                        isa.types.insert(type_name.clone(), HashMap::new());
+                        Section::Types(type_name)
+                    }
+                    "instructions" => {
+                        if !rest.is_empty(){
+                            println!("{}:{}:{} Unexpected \"{}\" in instructions section header", filename, row, col, rest);
+                            std::process::exit(1);
+                        }
+                        Section::Instructions
+                    }
+                    "pseudo" => {
+                        if rest.is_empty(){
+                            println!("{}:{}:{} You need to provide pseudo instruction name", filename, row, col);
+                            std::process::exit(1);
+                        }
+
+                        let pure_name = rest.split_whitespace().next().unwrap();
+
+                        if isa.pseudo_instructions.iter().any(|a| a.name.split_whitespace().next().unwrap() == pure_name){
+                            println!("{}:{}:{} Pseudo instruction {} already defined", filename, row, col, pure_name);
+                            std::process::exit(1);
+                        }
+
+                        isa.pseudo_instructions.push(IsaEntry { name: rest.to_string(), body: String::new(), filename: filename.to_string(), row, col: col + line[col..].find(rest).unwrap() + 1 });
+                        Section::Pseudo(isa.pseudo_instructions.len()-1)
+                    }
+                    _ => {
+                        println!("{}:{}:{} Unknown section {}", filename, row, col, kind);
+                        std::process::exit(1);
+                    }
+                };
+
+                continue;
+            }
+
+            if let Section::Pseudo(index) = section{
+                // bodies are lexed later so keep rows aligned with the file
+                let entry = &mut isa.pseudo_instructions[index];
+                entry.body += "\n";
+                entry.body += line;
+                continue;
+            }
+
+            if trimmed.is_empty() || trimmed.starts_with("//"){
+                continue;
+            }
+
+            let col = line.len() - line.trim_start().len() + 1;
+
+            let (key, value) = match trimmed.split_once('='){
+                Some((key, value)) => (key.trim(), value),
+                None => {
+                    println!("{}:{}:{} Expected \"name = value\"", filename, row, col);
+                    std::process::exit(1);
+                }
+            };
+
+            let value_col = line.find('=').unwrap() + 2 + value.len() - value.trim_start().len();
+            let value = match value.find("//"){
+                Some(a) => value[..a].trim(),
+                None => value.trim()
+            };
+
+            if key.is_empty(){
+                println!("{}:{}:{} Expected name before \"=\"", filename, row, col);
+                std::process::exit(1);
+            }
+
+            if value.is_empty(){
+                println!("{}:{}:{} Expected value after \"=\"", filename, row, value_col);
+                std::process::exit(1);
+            }
+
+            match &section{
+                Section::None => {
+                    println!("{}:{}:{} Expected section header before \"{}\"", filename, row, col, key);
+                    std::process::exit(1);
+                }
+                Section::Isa => {
+                    match key.to_lowercase().as_str(){
+                        "name" => {
+                            if value.contains(char::is_whitespace){
+                                println!("{}:{}:{} ISA name can't contain whitespace", filename, row, value_col);
+                                std::process::exit(1);
+                            }
+                            isa.name = value.to_string();
+                        }
+                        "word" => {
+                            isa.word_size = match parse_isa_number(value){
+                                Some(a) if a > 0 && a <= 64 => a,
+                                _ => {
+                                    println!("{}:{}:{} Expected word size between 1 and 64 got {}", filename, row, value_col, value);
+                                    std::process::exit(1);
+                                }
+                            };
+                        }
+                        "addressing" => {
+                            isa.word_addressed = match value.to_lowercase().as_str(){
+                                "byte" => false,
+                                "word" => true,
+                                _ => {
+                                    println!("{}:{}:{} Expected byte or word addressing got {}", filename, row, value_col, value);
+                                    std::process::exit(1);
+                                }
+                            };
+                        }
+                        "width" => {
+                            isa.instruction_width = match parse_isa_number(value){
+                                Some(a) if a > 0 && a <= 64 => Some(a),
+                                _ => {
+                                    println!("{}:{}:{} Expected instruction width between 1 and 64 got {}", filename, row, value_col, value);
+                                    std::process::exit(1);
+                                }
+                            };
+                        }
+                        "endian" | "data_endian" => {
+                            let little = match value.to_lowercase().as_str(){
+                                "little" => true,
+                                "big" => false,
+                                _ => {
+                                    println!("{}:{}:{} Expected little or big endian got {}", filename, row, value_col, value);
+                                    std::process::exit(1);
+                                }
+                            };
+
+                            if key.to_lowercase() == "endian"{
+                                isa.little_endian = little;
+                            }else{
+                                isa.data_little_endian = Some(little);
+                            }
+                        }
+                        _ => {
+                            println!("{}:{}:{} Unknown ISA setting {}", filename, row, col, key);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Section::Types(type_name) => {
+                    let val = match parse_isa_number(value){
+                        Some(a) => a,
+                        None => {
+                            println!("{}:{}:{} Expected number got {}", filename, row, value_col, value);
+                            std::process::exit(1);
+                        }
+                    };
+
+                    let type_values = isa.types.get_mut(type_name).unwrap();
+
+                    if type_values.insert(key.to_lowercase(), val).is_some(){
+                        println!("{}:{}:{} {} already defined in type {}", filename, row, col, key, type_name);
+                        std::process::exit(1);
+                    }
+                }
+                Section::Instructions => {
+                    // repeated mnemonic adds another form of overloaded instruction
+                    isa.instructions.push(IsaEntry { name: key.to_string(), body: value.to_string(), filename: filename.to_string(), row, col: value_col });
+                }
+                Section::Pseudo(_) => unreachable!()
+            }
+        }
+
+        if !isa.word_addressed && !isa.word_size.is_multiple_of(8){
+            println!("{}: {} bit words need word addressing", filename, isa.word_size);
+            std::process::exit(1);
+        }
+
+        if let Some(width) = isa.instruction_width{
+            if width % isa.word_size != 0{
+                println!("{}: instruction width {} has to be multiple of {} bit word", filename, width, isa.word_size);
+                std::process::exit(1);
+            }
+        }
+
+        if isa.instructions.is_empty(){
+            println!("{}: ISA doesn't define any instructions", filename);
+            std::process::exit(1);
+        }
+
+        isa
+    }
+}
+
+#[derive(Debug)]
+pub struct IsaRegistry{
+    pub isas: Vec<Isa>
+}
+
+impl IsaRegistry{
+    pub fn new() -> IsaRegistry{
+        IsaRegistry { isas: vec![Isa::builtin()] }
+    }
+
+    pub fn add(self: &mut Self, isa: Isa){
+        if let Some(a) = self.get(&isa.name){
+            println!("{}: ISA {} already defined in {}", isa.filename, isa.name, a.filename);
+            std::process::exit(1);
+        }
+
+        self.isas.push(isa);
+    }
+
+    pub fn load_dir(self: &mut Self, path: &Path){
+        let dir = match std::fs::read_dir(path){
+            Ok(a) => a,
+            Err(e) => {
+                println!("{}: Couldn't read ISA directory: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+
+        let mut paths: Vec<std::path::PathBuf> = dir
+            .filter_map(|a| a.ok())
+            .map(|a| a.path())
+            .filter(|a| a.extension().is_some_and(|ext| ext == "isa"))
+            .collect();
+
+        paths.sort();
+
+        for path in paths{
+            self.add(Isa::load(&path));
+        }
+    }
+
+    pub fn get(self: &Self, name: &str) -> Option<&Isa>{
+        self.isas.iter().find(|a| a.name == name)
+    }
+
+    pub fn names(self: &Self) -> Vec<&str>{
+        self.isas.iter().map(|a| a.name.as_str()).collect()
+    }
+}
+
+// `.cpu <name>` has to be known before pseudo instructions and instruction tables are built
+pub fn find_cpu_directive(lexems: &[Lexem]) -> Option<Lexem>{
+    let mut selected: Option<Lexem> = None;
+    let mut line_start = true;
+
+    // conditions aren't evaluated yet, so ISA can't depend on them
+    let mut conditionals = 0;
+
+    for (i, lexem) in lexems.iter().enumerate(){
+        if lexem.ttype == LexemType::NewLine{
+            line_start = true;
+            continue;
+        }
+
+        if line_start && lexem.ttype == LexemType::Ident{
+            match lexem.value.to_lowercase().as_str(){
+                ".if" | ".ifdef" | ".ifndef" => conditionals += 1,
+                ".endif" => conditionals -= 1,
+                ".cpu" if conditionals > 0 => {
+                    println!("{}:{}:{} .cpu can't be inside conditional block", lexem.filename, lexem.row, lexem.col);
+                    std::process::exit(1);
+                }
+                _ => {}
+            }
+        }
+
+        if line_start && lexem.ttype == LexemType::Ident && lexem.value.to_lowercase() == ".cpu"{
+            let name = match lexems.get(i+1){
+                Some(a) if a.ttype == LexemType::Ident || a.ttype == LexemType::String => a.clone(),
+                _ => {
+                    println!("{}:{}:{} .cpu expects ISA name", lexem.filename, lexem.row, lexem.col);
+                    std::process::exit(1);
+                }
+            };
+
+            if let Some(a) = &selected{
+                if a.value != name.value{
+                    println!("{}:{}:{} ISA {} was already selected at {}:{}:{}", name.filename, name.row, name.col, a.value, a.filename, a.row, a.col);
+                    std::process::exit(1);
+                }
+            }
+
+            selected = Some(name);
+        }
+
+        line_start = false;
+    }
+
+    selected
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use crate::{data_bits, get_value_from_number_token, Instruction, InstructionPart, InstructionsLexer, Isa, LexemType, Token, DIRECTIVES, SYMBOL_DIRECTIVES};
+
+// one column of bit-layout diagram, bits hi..=lo of the instruction
+struct Field{
+    hi: usize,
+    lo: usize,
+    label: String
+}
+
+fn escape_html(str: &str) -> String{
+    str.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn bit_range(hi: usize, lo: usize) -> String{
+    if hi == lo{
+        hi.to_string()
+    }else{
+        format!("{}..{}", hi, lo)
+    }
+}
+
+fn operand_description(operand: &InstructionPart) -> String{
+    match operand{
+        InstructionPart::Imm { size } => format!("unsigned immediate, {} bits (0..={})", size, (1u128 << size) - 1),
+        InstructionPart::SImm { size } => format!("signed immediate, {} bits ({}..={})", size, -(1i128 << (size - 1)), (1i128 << (size - 1)) - 1),
+        InstructionPart::Rel { size } => format!("offset from instruction address, {} bits ({}..={})", size, -(1i128 << (size - 1)), (1i128 << (size - 1)) - 1),
+        InstructionPart::Type { val, size } => format!("name from type {}, {} bits", val, size),
+        InstructionPart::Extra { size } => format!("optional immediate, {} bits (0 when left out)", size),
+        InstructionPart::Const { .. } | InstructionPart::Slice { .. } => unreachable!()
+    }
+}
+
+pub struct IsaDocs<'a>{
+    isa: &'a Isa,
+    instruction_lexer: &'a InstructionsLexer,
+    pseudo_instructions: &'a HashMap<String, (Vec<String>,Vec<Token>)>
+}
+
+impl IsaDocs<'_>{
+    pub fn new<'a>(isa: &'a Isa, instruction_lexer: &'a InstructionsLexer, pseudo_instructions: &'a HashMap<String, (Vec<String>,Vec<Token>)>) -> IsaDocs<'a>{
+        IsaDocs{
+            isa,
+            instruction_lexer,
+            pseudo_instructions
+        }
+    }
+
+    fn units_str(self: &Self, count: usize) -> String{
+        let unit = if self.instruction_lexer.word_addressed {"word"} else {"byte"};
+
+        if count == 1{
+            format!("{} {}", count, unit)
+        }else{
+            format!("{} {}s", count, unit)
+        }
+    }
+
+    fn sorted_instructions(self: &Self) -> Vec<(&'_ String, &'_ Vec<Instruction>)>{
+        let mut instructions: Vec<(&String, &Vec<Instruction>)> = self.instruction_lexer.instructions.iter().collect();
+        instructions.sort_by(|a, b| a.0.cmp(b.0));
+        instructions
+    }
+
+    fn sorted_types(self: &Self) -> Vec<(&'_ String, Vec<(&'_ String, &'_ usize)>)>{
+        let mut types: Vec<(&String, Vec<(&String, &usize)>)> = self.instruction_lexer.types.iter().map(|(name, values)| {
+            let mut values: Vec<(&String, &usize)> = values.iter().collect();
+            values.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+            (name, values)
+        }).collect();
+        types.sort_by(|a, b| a.0.cmp(b.0));
+        types
+    }
+
+    // pseudo instruction header (`lim imm`) and its body lines as written in ISA
+    fn sorted_pseudo_instructions(self: &Self) -> Vec<(String, Vec<String>)>{
+        let mut pseudo_instructions: Vec<(String, Vec<String>)> = self.isa.pseudo_instructions.iter().map(|entry| {
+            let body = entry.body.lines().map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect();
+            (entry.name.trim().to_string(), body)
+        }).collect();
+        pseudo_instructions.sort_by(|a, b| a.0.cmp(&b.0));
+        pseudo_instructions
+    }
+
+    // bit layout from msb to lsb, neighbouring constant bits make one field
+    fn fields(self: &Self, instruction: &Instruction) -> Vec<Field>{
+        let bits = self.instruction_lexer.instruction_bits(instruction);
+        let mut fields: Vec<Field> = Vec::new();
+        let mut cursor = bits;
+
+        if bits > instruction.size(){
+            fields.push(Field { hi: bits - 1, lo: instruction.size(), label: "0".repeat(bits - instruction.size()) });
+            cursor = instruction.size();
+        }
+
+        for part in instruction.parts.iter(){
+            match part{
+                InstructionPart::Const { val } => {
+                    if val.is_empty(){
+                        continue;
+                    }
+
+                    match fields.last_mut(){
+                        Some(last) if last.label.chars().all(|a| a == '0' || a == '1') => {
+                            last.lo -= val.len();
+                            last.label += val;
+                        }
+                        _ => fields.push(Field { hi: cursor - 1, lo: cursor - val.len(), label: val.clone() })
+                    }
+                    cursor -= val.len();
+                }
+
+                InstructionPart::Slice { operand, hi, lo } => {
+                    let width = hi - lo + 1;
+                    let operand_part = &instruction.operands[*operand];
+
+                    let mut label = operand_part.to_string();
+
+                    if instruction.operands.len() > 1{
+                        label = format!("@{} {}", operand + 1, label);
+                    }
+
+                    let operand_size = match operand_part{
+                        InstructionPart::Imm { size } | InstructionPart::SImm { size } | InstructionPart::Rel { size }
+                        | InstructionPart::Type { size, .. } | InstructionPart::Extra { size } => *size,
+                        _ => unreachable!()
+                    };
+
+                    if width != operand_size{
+                        label += format!("[{}:{}]", hi, lo).as_str();
+                    }
+
+                    fields.push(Field { hi: cursor - 1, lo: cursor - width, label });
+                    cursor -= width;
+                }
+
+                _ => unreachable!()
+            }
+        }
+
+        fields
+    }
+
+    // smallest and largest number of addresses the body can take
+    fn expansion_size(self: &Self, name: &str, depth: usize) -> Option<(usize, usize)>{
+        if depth > 16{
+            return None;
+        }
+
+        let (params, body) = self.pseudo_instructions.get(name)?;
+
+        self.block_size(params, body, depth)
+    }
+
+    fn block_size(self: &Self, params: &Vec<String>, body: &[Token], depth: usize) -> Option<(usize, usize)>{
+        let mut min = 0;
+        let mut max = 0;
+
+        let mut i = 0;
+
+        while i < body.len(){
+            let Token::Instruction { name, args } = &body[i] else {
+                i += 1;
+                continue;
+            };
+
+            if params.contains(&name.value){
+                return None;
+            }
+
+            let (a, b) = match name.value.to_lowercase().as_str(){
+                keyword if data_bits(keyword).is_some() => {
+                    let size = args.len() * self.instruction_lexer.data_units(data_bits(keyword).unwrap());
+                    (size, size)
+                }
+                // `.rept` with literal count, body ends at matching `.endr`
+                ".rept" if args.len() == 1 && matches!(args[0].ttype, LexemType::Number { .. }) => {
+                    let count = get_value_from_number_token(&args[0]);
+                    let mut nesting = 0;
+
+                    let end = body[i+1..].iter().position(|a| {
+                        let Token::Instruction { name, .. } = a else { return false };
+                        match name.value.to_lowercase().as_str(){
+                            ".rept" | ".irp" | ".irpc" => nesting += 1,
+                            ".endr" if nesting == 0 => return true,
+                            ".endr" => nesting -= 1,
+                            _ => {}
+                        }
+                        false
+                    })? + i + 1;
+
+                    let (a, b) = self.block_size(params, &body[i+1..end], depth)?;
+                    i = end;
+                    (a * count, b * count)
+                }
+                keyword if SYMBOL_DIRECTIVES.contains(&keyword) => (0, 0),
+                // size of other directives depends on values only known when assembling
+                keyword if DIRECTIVES.contains(&keyword) => return None,
+                _ => match self.instruction_lexer.instructions.get(&name.value){
+                    Some(forms) => {
+                        let sizes = forms.iter().map(|a| self.instruction_lexer.instruction_units(a));
+                        (sizes.clone().min().unwrap(), sizes.max().unwrap())
+                    }
+                    None => self.expansion_size(&name.value, depth + 1)?
+                }
+            };
+
+            min += a;
+            max += b;
+            i += 1;
+        }
+
+        Some((min, max))
+    }
+
+    fn expansion_size_str(self: &Self, name: &str) -> String{
+        match self.expansion_size(name, 0){
+            Some((min, max)) if min == max => self.units_str(min),
+            Some((min, max)) => format!("{} to {}", min, self.units_str(max)),
+            None => "unknown".to_string()
+        }
+    }
+
+    fn properties(self: &Self) -> Vec<(&'static str, String)>{
+        let mut properties: Vec<(&'static str, String)> = Vec::new();
+
+        properties.push(("Word size", format!("{} bits", self.instruction_lexer.word_size)));
+        properties.push(("Addressing", (if self.instruction_lexer.word_addressed {"word"} else {"byte"}).to_string()));
+
+        if let Some(width) = self.instruction_lexer.instruction_width{
+            properties.push(("Instruction width", format!("{} bits", width)));
+        }
+
+        properties.push(("Instruction byte order", (if self.instruction_lexer.little_endian {"little endian"} else {"big endian"}).to_string()));
+        properties.push(("Data byte order", (if self.instruction_lexer.data_little_endian {"little endian"} else {"big endian"}).to_string()));
+
+        properties
+    }
+
+    pub fn markdown(self: &Self) -> String{
+        let mut out = String::new();
+
+        out += format!("# {} instruction set\n\n", self.isa.name).as_str();
+        out += format!("Generated from `{}`.\n\n", self.isa.filename).as_str();
+
+        out += "| Property | Value |\n|---|---|\n";
+        for (name, value) in self.properties(){
+            out += format!("| {} | {} |\n", name, value).as_str();
+        }
+
+        out += "\n## Instructions\n\n";
+
+        for (name, forms) in self.sorted_instructions(){
+            out += format!("### {}\n\n", name).as_str();
+
+            for form in forms{
+                out += format!("`{}` ({})\n\n", form.signature(name), self.units_str(self.instruction_lexer.instruction_units(form))).as_str();
+
+                let fields = self.fields(form);
+
+                out += "|";
+                for field in fields.iter(){
+                    out += format!(" {} |", bit_range(field.hi, field.lo)).as_str();
+                }
+                out += "\n|";
+                for _ in fields.iter(){
+                    out += "---|";
+                }
+                out += "\n|";
+                for field in fields.iter(){
+                    out += format!(" `{}` |", field.label).as_str();
+                }
+                out += "\n\n";
+
+                for (i, operand) in form.operands.iter().enumerate(){
+                    out += format!("- @{} `{}`: {}\n", i + 1, operand, operand_description(operand)).as_str();
+                }
+
+                if !form.operands.is_empty(){
+                    out += "\n";
+                }
+            }
+        }
+
+        out += "## Types\n\n";
+
+        for (name, values) in self.sorted_types(){
+            out += format!("### {}\n\n| Name | Value |\n|---|---|\n", name).as_str();
+
+            for (value_name, value) in values{
+                out += format!("| `{}` | {} |\n", value_name, value).as_str();
+            }
+
+            out += "\n";
+        }
+
+        out += "## Pseudo instructions\n\n";
+
+        for (header, body) in self.sorted_pseudo_instructions(){
+            let pure_name = header.split(' ').next().unwrap();
+
+            out += format!("### {}\n\n", header).as_str();
+            out += format!("Size: {}\n\n", self.expansion_size_str(pure_name)).as_str();
+            out += format!("```\n{}\n```\n\n", body.join("\n")).as_str();
+        }
+
+        out
+    }
+
+    pub fn html(self: &Self) -> String{
+        let mut out = String::new();
+
+        out += "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n";
+        out += format!("<title>{} instruction set</title>\n", escape_html(&self.isa.name)).as_str();
+        out += "<style>\n";
+        out += "body { font-family: sans-serif; max-width: 60em; margin: auto; }\n";
+        out += "table { border-collapse: collapse; margin-bottom: 1em; }\n";
+        out += "td, th { border: 1px solid #888; padding: 0.2em 0.6em; text-align: center; }\n";
+        out += "td.operand { background: #e8f0ff; }\n";
+        out += "</style>\n</head>\n<body>\n";
+
+        out += format!("<h1>{} instruction set</h1>\n", escape_html(&self.isa.name)).as_str();
+        out += format!("<p>Generated from <code>{}</code>.</p>\n", escape_html(&self.isa.filename)).as_str();
+
+        out += "<table>\n<tr><th>Property</th><th>Value</th></tr>\n";
+        for (name, value) in self.properties(){
+            out += format!("<tr><td>{}</td><td>{}</td></tr>\n", name, escape_html(&value)).as_str();
+        }
+        out += "</table>\n";
+
+        out += "<h2>Instructions</h2>\n";
+
+        for (name, forms) in self.sorted_instructions(){
+            out += format!("<h3 id=\"{0}\">{0}</h3>\n", escape_html(name)).as_str();
+
+            for form in forms{
+                out += format!("<p><code>{}</code> ({})</p>\n", escape_html(&form.signature(name)), self.units_str(self.instruction_lexer.instruction_units(form))).as_str();
+
+                let fields = self.fields(form);
+
+                out += "<table>\n<tr>";
+                for field in fields.iter(){
+                    out += format!("<th>{}</th>", bit_range(field.hi, field.lo)).as_str();
+                }
+                out += "</tr>\n<tr>";
+                for field in fields.iter(){
+                    let constant = field.label.chars().all(|a| a == '0' || a == '1');
+                    out += format!("<td{}><code>{}</code></td>", if constant {""} else {" class=\"operand\""}, escape_html(&field.label)).as_str();
+                }
+                out += "</tr>\n</table>\n";
+
+                if !form.operands.is_empty(){
+                    out += "<ul>\n";
+                    for (i, operand) in form.operands.iter().enumerate(){
+                        out += format!("<li>@{} <code>{}</code>: {}</li>\n", i + 1, escape_html(&operand.to_string()), escape_html(&operand_description(operand))).as_str();
+                    }
+                    out += "</ul>\n";
+                }
+            }
+        }
+
+        out += "<h2>Types</h2>\n";
+
+        for (name, values) in self.sorted_types(){
+            out += format!("<h3>{}</h3>\n<table>\n<tr><th>Name</th><th>Value</th></tr>\n", escape_html(name)).as_str();
+
+            for (value_name, value) in values{
+                out += format!("<tr><td><code>{}</code></td><td>{}</td></tr>\n", escape_html(value_name), value).as_str();
+            }
+
+            out += "</table>\n";
+        }
+
+        out += "<h2>Pseudo instructions</h2>\n";
+
+        for (header, body) in self.sorted_pseudo_instructions(){
+            let pure_name = header.split(' ').next().unwrap();
+
+            out += format!("<h3>{}</h3>\n", escape_html(&header)).as_str();
+            out += format!("<p>Size: {}</p>\n", self.expansion_size_str(pure_name)).as_str();
+            out += format!("<pre>{}</pre>\n", escape_html(&body.join("\n"))).as_str();
+        }
+
+        out += "</body>\n</html>\n";
+
+        out
+    }
+}
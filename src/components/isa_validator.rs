@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use crate::{Instruction, InstructionPart, InstructionsLexer, PseudoInstruction, Token, DIRECTIVES};
+
+#[derive(Debug, Clone)]
+pub struct IsaDiagnostic{
+    pub error: bool,
+    pub message: String
+}
+
+impl std::fmt::Display for IsaDiagnostic{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        if self.error{
+            write!(f, "Error: {}", self.message)
+        }else{
+            write!(f, "Warning: {}", self.message)
+        }
+    }
+}
+
+// range of values accepted by immediate operand
+fn operand_range(operand: &InstructionPart) -> Option<(i128, i128)>{
+    match operand{
+        InstructionPart::Imm { size } | InstructionPart::Extra { size } => Some((0, (1i128 << size) - 1)),
+        InstructionPart::SImm { size } | InstructionPart::Rel { size } => Some((-(1i128 << (size - 1)), (1i128 << (size - 1)) - 1)),
+        _ => None
+    }
+}
+
+fn location(instruction: &Instruction) -> String{
+    format!("{}:{}:{}", instruction.filename, instruction.row, instruction.col)
+}
+
+pub struct IsaValidator<'a>{
+    instruction_lexer: &'a InstructionsLexer,
+    pseudo_instructions: &'a HashMap<String, (Vec<String>,Vec<Token>)>,
+    pub diagnostics: Vec<IsaDiagnostic>
+}
+
+impl IsaValidator<'_>{
+    pub fn new<'a>(instruction_lexer: &'a InstructionsLexer, pseudo_instructions: &'a HashMap<String, (Vec<String>,Vec<Token>)>) -> IsaValidator<'a>{
+        IsaValidator{
+            instruction_lexer,
+            pseudo_instructions,
+            diagnostics: Vec::new()
+        }
+    }
+
+    fn error(self: &mut Self, message: String){
+        self.diagnostics.push(IsaDiagnostic { error: true, message });
+    }
+
+    fn warning(self: &mut Self, message: String){
+        self.diagnostics.push(IsaDiagnostic { error: false, message });
+    }
+
+    pub fn has_errors(self: &Self) -> bool{
+        self.diagnostics.iter().any(|a| a.error)
+    }
+
+    // instructions sorted by mnemonic so reports don't depend on hash order
+    fn sorted_instructions(self: &Self) -> Vec<(&'_ String, &'_ Vec<Instruction>)>{
+        let mut instructions: Vec<(&String, &Vec<Instruction>)> = self.instruction_lexer.instructions.iter().collect();
+        instructions.sort_by(|a, b| a.0.cmp(b.0));
+        instructions
+    }
+
+    // every bit of instruction as it lands in memory: '0', '1' or 'x' for operand bits
+    fn bit_pattern(self: &Self, instruction: &Instruction) -> String{
+        let mut pattern = String::new();
+
+        for part in instruction.parts.iter(){
+            match part{
+                InstructionPart::Const { val } => pattern += val,
+                InstructionPart::Slice { operand: _, hi, lo } => pattern += "x".repeat(hi - lo + 1).as_str(),
+                _ => unreachable!()
+            }
+        }
+
+        let bits = self.instruction_lexer.instruction_bits(instruction);
+
+        if pattern.len() < bits{
+            pattern = "0".repeat(bits - pattern.len()) + pattern.as_str();
+        }
+
+        pattern
+    }
+
+    fn check_widths(self: &mut Self){
+        let Some(width) = self.instruction_lexer.instruction_width else {
+            return;
+        };
+
+        let mut messages: Vec<String> = Vec::new();
+
+        for (name, forms) in self.sorted_instructions(){
+            for form in forms{
+                if form.size() > width{
+                    messages.push(format!("{} encoding of {} has {} bits but instructions are {} bits wide", location(form), form.signature(name), form.size(), width));
+                }
+            }
+        }
+
+        for message in messages{
+            self.error(message);
+        }
+    }
+
+    fn check_type_values(self: &mut Self){
+        let mut messages: Vec<String> = Vec::new();
+
+        for (name, forms) in self.sorted_instructions(){
+            for form in forms{
+                for operand in form.operands.iter(){
+                    let InstructionPart::Type { val, size } = operand else {
+                        continue;
+                    };
+
+                    let mut values: Vec<(&String, &usize)> = self.instruction_lexer.types[val].iter().collect();
+                    values.sort();
+
+                    for (value_name, value) in values{
+                        if *size < usize::BITS as usize && *value >= (1usize << size){
+                            messages.push(format!("{} {} {} = {} doesn't fit in {} bit field of {}", location(form), val, value_name, value, size, form.signature(name)));
+                        }
+                    }
+                }
+            }
+        }
+
+        for message in messages{
+            self.warning(message);
+        }
+    }
+
+    fn check_uncovered_bits(self: &mut Self){
+        let mut messages: Vec<String> = Vec::new();
+
+        for (name, forms) in self.sorted_instructions(){
+            for form in forms{
+                for operand in 0..form.operands.len(){
+                    let bits = form.uncovered_bits(operand);
+
+                    if !bits.is_empty(){
+                        let bits: Vec<String> = bits.iter().rev().map(|a| a.to_string()).collect();
+                        let bits = match bits.len(){
+                            1 => format!("bit {} has", bits[0]),
+                            _ => format!("bits {} have", bits.join(", "))
+                        };
+                        messages.push(format!("{} operand @{} of {} is only partly encoded, {} to be 0", location(form), operand + 1, form.signature(name), bits));
+                    }
+                }
+            }
+        }
+
+        for message in messages{
+            self.warning(message);
+        }
+    }
+
+    fn check_overlaps(self: &mut Self){
+        let mut encodings: Vec<(&String, &Instruction, String)> = Vec::new();
+
+        for (name, forms) in self.sorted_instructions(){
+            for form in forms{
+                encodings.push((name, form, self.bit_pattern(form)));
+            }
+        }
+
+        let mut messages: Vec<String> = Vec::new();
+
+        for (i, (name_a, form_a, pattern_a)) in encodings.iter().enumerate(){
+            for (name_b, form_b, pattern_b) in encodings[i+1..].iter(){
+                if name_a == name_b || pattern_a.len() != pattern_b.len(){
+                    continue;
+                }
+
+                let conflict = pattern_a.chars().zip(pattern_b.chars()).all(|(a, b)| a == 'x' || b == 'x' || a == b);
+
+                if conflict{
+                    messages.push(format!("{} encoding of {} ({}) overlaps with {} ({}) at {}", location(form_b), form_b.signature(name_b), pattern_b, form_a.signature(name_a), pattern_a, location(form_a)));
+                }
+            }
+        }
+
+        for message in messages{
+            self.warning(message);
+        }
+    }
+
+    // form can't be picked when earlier form with the same template accepts the same operands
+    fn check_unreachable(self: &mut Self){
+        let mut messages: Vec<String> = Vec::new();
+
+        for (name, forms) in self.sorted_instructions(){
+            for (j, later) in forms.iter().enumerate(){
+                for earlier in forms[..j].iter(){
+                    if earlier.pattern != later.pattern || earlier.operands.len() != later.operands.len(){
+                        continue;
+                    }
+
+                    let same_kinds = earlier.operands.iter().zip(later.operands.iter()).all(|(a, b)| {
+                        match (a, b){
+                            (InstructionPart::Type { val: a, .. }, InstructionPart::Type { val: b, .. }) => a == b,
+                            (a, b) => operand_range(a).is_some() && operand_range(b).is_some()
+                        }
+                    });
+
+                    if !same_kinds{
+                        continue;
+                    }
+
+                    let same_relative = earlier.operands.iter().zip(later.operands.iter())
+                        .all(|(a, b)| matches!(a, InstructionPart::Rel { .. }) == matches!(b, InstructionPart::Rel { .. }));
+
+                    let covers = earlier.operands.iter().zip(later.operands.iter()).all(|(a, b)| {
+                        match (a, b){
+                            (InstructionPart::Type { size: a, .. }, InstructionPart::Type { size: b, .. }) => a >= b,
+                            (a, b) => {
+                                let (a_min, a_max) = operand_range(a).unwrap();
+                                let (b_min, b_max) = operand_range(b).unwrap();
+                                a_min <= b_min && a_max >= b_max
+                            }
+                        }
+                    });
+
+                    if !same_relative || covers{
+                        messages.push(format!("{} {} is unreachable, {} at {} is always picked first", location(later), later.signature(name), earlier.signature(name), location(earlier)));
+                        break;
+                    }
+                }
+            }
+        }
+
+        for message in messages{
+            self.warning(message);
+        }
+    }
+
+    fn check_pseudo_instructions(self: &mut Self){
+        let mut pseudo_instructions: Vec<(&String, &PseudoInstruction)> = self.pseudo_instructions.iter().collect();
+        pseudo_instructions.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut messages: Vec<String> = Vec::new();
+
+        for (pseudo_name, (args, body)) in pseudo_instructions{
+            for token in body{
+                let Token::Instruction { name, .. } = token else {
+                    continue;
+                };
+
+                let known = self.instruction_lexer.instructions.contains_key(&name.value)
+                    || self.pseudo_instructions.contains_key(&name.value)
+                    || DIRECTIVES.contains(&name.value.to_lowercase().as_str())
+                    || args.contains(&name.value);
+
+                if !known{
+                    messages.push(format!("{}:{}:{} pseudo instruction {} uses unknown instruction {}", name.filename, name.row, name.col, pseudo_name, name.value));
+                }
+            }
+        }
+
+        for message in messages{
+            self.error(message);
+        }
+    }
+
+    pub fn validate(self: &mut Self){
+        self.diagnostics.clear();
+
+        self.check_widths();
+        self.check_type_values();
+        self.check_uncovered_bits();
+        self.check_overlaps();
+        self.check_unreachable();
+        self.check_pseudo_instructions();
+    }
+}
+
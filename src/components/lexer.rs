@@ -1,6 +1,12 @@
-pub const SINGLE_LEXEMS: &[char] = &[',',':', '(', ')', '$'];
+use std::path::{Path, PathBuf};
 
-pub const OP_LEXEMS: &[&'static str] = &["+", "-", "/", "*", "&", "|", "^","<<", ">>"];
+pub const SINGLE_LEXEMS: &[char] = &[',',':', '(', ')', '$', '[', ']'];
+
+// lexems that can be part of operand templates (`+` only between brackets)
+pub const PUNCTUATION_LEXEMS: &[&str] = &["[", "]", ":"];
+
+// longer operators first so `<<` isn't lexed as two `<`
+pub const OP_LEXEMS: &[&'static str] = &["<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "/", "*", "%", "&", "|", "^", "~", "!", "<", ">", "?", "="];
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum LexemType{
@@ -11,13 +17,15 @@ pub enum LexemType{
     },
     String,
     Operator,
+    // expression, args are operands and operators in source order
+    // e.g. [lhs, op, rhs]
     Closure {
-        args: [Box<Lexem>; 3]
+        args: Vec<Lexem>
     },
     NewLine
 }
 
-impl PartialEq for Box<Lexem>{
+impl PartialEq for Lexem{
     fn eq(&self, other: &Self) -> bool {
         self.ttype.eq(&other.ttype)
     }
@@ -59,6 +67,67 @@ pub struct Lexer{
     row: usize,
     col: usize,
     pub lexems: Vec<Lexem>,
+    // `$FF` is hex number instead of current address followed by label
+    pub dollar_hex: bool,
+}
+
+// digits can be separated with `_`, literal has to fit in 64 bits
+pub fn number_lexem(lexem: &str, digits_start: usize, digits: &str, radix: u32, row: usize, col: usize, filename: &str) -> Lexem{
+    let kind = match radix{
+        16 => "hexlit",
+        8 => "octlit",
+        2 => "binlit",
+        _ => "number"
+    };
+
+    for (i, ch) in digits.chars().enumerate(){
+        if ch != '_' && !ch.is_digit(radix){
+            println!("{}:{}:{} Expected {} got {}", filename, row, col+digits_start+i, kind, ch);
+            std::process::exit(1);
+        }
+    }
+
+    let value: String = digits.chars().filter(|a| *a != '_').collect();
+
+    if value.is_empty(){
+        println!("{}:{}:{} Expected {} got {}", filename, row, col, kind, lexem);
+        std::process::exit(1);
+    }
+
+    if u64::from_str_radix(&value, radix).is_err(){
+        println!("{}:{}:{} Number {} doesn't fit in 64 bits", filename, row, col, lexem);
+        std::process::exit(1);
+    }
+
+    Lexem::new(value, LexemType::Number { radix: radix as usize }, row, col, filename.to_string())
+}
+
+// suffix forms 0FFh, 1010b; prefix forms 0xFF, 0o17, 0b1010
+pub fn word_number(lexem: &str, row: usize, col: usize, filename: &str) -> Lexem{
+    let lower = lexem.to_lowercase();
+
+    if lower.ends_with('h'){
+        number_lexem(lexem, 0, &lexem[..lexem.len()-1], 16, row, col, filename)
+    }else if lower.starts_with("0x"){
+        number_lexem(lexem, 2, &lexem[2..], 16, row, col, filename)
+    }else if lower.starts_with("0o"){
+        number_lexem(lexem, 2, &lexem[2..], 8, row, col, filename)
+    }else if lower.starts_with("0b"){
+        number_lexem(lexem, 2, &lexem[2..], 2, row, col, filename)
+    }else if lower.ends_with('b'){
+        number_lexem(lexem, 0, &lexem[..lexem.len()-1], 2, row, col, filename)
+    }else{
+        number_lexem(lexem, 0, lexem, 10, row, col, filename)
+    }
+}
+
+// decimal digits followed by `b` / `f`, either binary literal or local label reference
+pub fn is_local_reference(word: &str) -> bool{
+    let Some(digits) = word.strip_suffix(['b', 'f']) else{
+        return false;
+    };
+
+    !digits.is_empty() && digits.chars().all(|a| a.is_ascii_digit())
 }
 
 impl Lexer{
@@ -69,7 +138,8 @@ impl Lexer{
             cursor: 0,
             row: 1,
             col: 1,
-            lexems: Vec::new()
+            lexems: Vec::new(),
+            dollar_hex: false
         }
     }
 
@@ -144,13 +214,41 @@ impl Lexer{
         false
     }
 
+    fn push_number(self: &mut Self, lexem: &str, digits_start: usize, digits: &str, radix: u32, row: usize, col: usize){
+        self.lexems.push(number_lexem(lexem, digits_start, digits, radix, row, col, &self.source_filename));
+    }
+
+    fn chop_dollar_hex(self: &mut Self) -> bool{
+        if !self.dollar_hex || self.cursor + 1 >= self.content.len(){
+            return false;
+        }
+
+        if self.peek().unwrap() != '$' || !self.content.chars().nth(self.cursor + 1).unwrap().is_ascii_hexdigit(){
+            return false;
+        }
+
+        let row = self.row;
+        let col = self.col;
+
+        let mut lexem = self.chop().to_string();
+
+        while self.cursor < self.content.len() && (self.peek().unwrap().is_alphanumeric() || self.peek().unwrap() == '_'){
+            lexem += self.chop().to_string().as_str();
+        }
+
+        self.push_number(&lexem, 1, &lexem[1..], 16, row, col);
+
+        true
+    }
+
     fn chop_word(self: &mut Self) -> bool{
         let mut lexem: String = String::new();
 
         let row = self.row;
         let col = self.col;
 
-        while self.cursor < self.content.len() && (self.peek().unwrap().is_alphanumeric() || self.peek().unwrap() == '.'){
+        // `_` is part of names (ARRAY_LEN) and separates digits of numbers
+        while self.cursor < self.content.len() && (self.peek().unwrap().is_alphanumeric() || self.peek().unwrap() == '.' || self.peek().unwrap() == '_'){
 
             lexem += self.chop().to_string().as_str();
         }
@@ -159,40 +257,17 @@ impl Lexer{
             return false;
         }
 
-        
-        if lexem.starts_with("0x"){
-            for (i, ch) in lexem.chars().skip(2).enumerate(){
-                if !ch.is_ascii_hexdigit(){
-                    println!("{}:{}:{} Expected hexlit got {}", self.source_filename, row, col+i+2, ch);
-                    std::process::exit(1);
-                }
-                
-            }
-            self.lexems.push(Lexem::new(lexem.chars().skip(2).collect(), LexemType::Number { radix: 16 }, row,col, self.source_filename.clone()));
-            return true;
-        }
-        
-        if lexem.starts_with("0b"){
-            for (i, ch) in lexem.chars().skip(2).enumerate(){
-                if ch != '0' && ch != '1' {
-                    println!("{}:{}:{} Expected binlit got {}", self.source_filename, row, col+i+2, ch);
-                    std::process::exit(1);
-                }
-                
-            }
-            self.lexems.push(Lexem::new(lexem.chars().skip(2).collect(), LexemType::Number { radix: 2 }, row,col, self.source_filename.clone()));
-            return true;
-        }
-        
         if lexem.chars().nth(0).unwrap().is_numeric(){
-            for (i, ch) in lexem.chars().enumerate(){
-                if !ch.is_numeric(){
-                    println!("{}:{}:{} Expected number got {}", self.source_filename, row, col+i, ch);
-                    std::process::exit(1);
-                }
+            let lower = lexem.to_lowercase();
 
+            // `1b` / `2f` can also be reference to local label, parser decides once it knows the labels
+            if is_local_reference(&lower){
+                self.lexems.push(Lexem::new(lower, LexemType::Ident, row, col, self.source_filename.clone()));
+                return true;
             }
-            self.lexems.push(Lexem::new(lexem, LexemType::Number { radix: 10 }, row,col, self.source_filename.clone()));
+
+            self.lexems.push(word_number(&lexem, row, col, &self.source_filename));
+
             return true;
         }
 
@@ -220,16 +295,21 @@ impl Lexer{
             return false;
         }
 
-        self.chop();
+        // string ends only with the same quote it started with
+        let delimiter = self.chop();
 
         let mut value = String::new();
 
-        while self.peek().unwrap() != '\"' && self.peek().unwrap() != '\''{
+        loop{
             if self.cursor >= self.content.len(){
-                println!("{}:{}:{} Expected \" got end of file", self.source_filename, self.row, self.col);
+                println!("{}:{}:{} Expected {} got end of file", self.source_filename, self.row, self.col, delimiter);
                 std::process::exit(1);
             }
 
+            if self.peek().unwrap() == delimiter{
+                break;
+            }
+
             if self.peek().unwrap() == '\\'{
                 self.chop();
                 if self.cursor >= self.content.len(){
@@ -244,6 +324,7 @@ impl Lexer{
                     '\'' => value += "\'",
                      a  => {
                         println!("{}:{}:{} Unexpected character {}", self.source_filename, self.row, self.col, a);
+                        std::process::exit(1);
                      }
                 };
 
@@ -256,6 +337,18 @@ impl Lexer{
 
         self.chop();
 
+        // 'A' is a number (character code)
+        if delimiter == '\''{
+            if value.chars().count() != 1{
+                println!("{}:{}:{} Character literal has to contain exactly one character, got '{}'", self.source_filename, row, col, value);
+                std::process::exit(1);
+            }
+
+            let ch = value.chars().next().unwrap();
+            self.lexems.push(Lexem::new((ch as u32).to_string(), LexemType::Number { radix: 10 }, row, col, self.source_filename.clone()));
+            return true;
+        }
+
         self.lexems.push(Lexem::new(value, LexemType::String, row, col, self.source_filename.clone() ));
 
         return true;
@@ -306,6 +399,8 @@ impl Lexer{
         self.seek_comments();
 
 
+        if self.chop_dollar_hex() {return}
+
         if self.chop_single() {return}
 
         if self.chop_pattern() {return}
@@ -325,7 +420,13 @@ impl Lexer{
     }
 
     pub fn lex<'a>(self: &mut Self, source_filename: &'a str, content: &'a str){
+        self.lex_at(source_filename, content, 1);
+    }
+
+    pub fn lex_at<'a>(self: &mut Self, source_filename: &'a str, content: &'a str, row: usize){
         self.cursor = 0;
+        self.row = row;
+        self.col = 1;
         self.content = content.to_string();
         self.lexems.clear();
         self.source_filename = source_filename.to_string();
@@ -333,4 +434,38 @@ impl Lexer{
             self.chop_lexem();
         }
     }
-}
\ No newline at end of file
+
+    // file relative to including file first, then include directories in order
+    pub fn find_include(including_file: &str, name: &Lexem, include_dirs: &[String]) -> String{
+        let mut candidates: Vec<PathBuf> = vec![Path::new(including_file).parent().unwrap_or(Path::new("")).join(&name.value)];
+
+        for dir in include_dirs{
+            candidates.push(Path::new(dir).join(&name.value));
+        }
+
+        match candidates.iter().find(|a| a.is_file()){
+            Some(a) => a.to_str().unwrap().to_string(),
+            None => {
+                let searched: Vec<String> = candidates.iter().map(|a| a.to_str().unwrap().to_string()).collect();
+                println!("{}:{}:{} Couldn't find file {} (searched: {})", name.filename, name.row, name.col, name.value, searched.join(", "));
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // `.include` and `.incbin` are resolved by parser once it's known they are assembled
+    pub fn lex_file(self: &mut Self, source_filename: &str, include: Option<&Lexem>){
+        let content = match std::fs::read_to_string(source_filename){
+            Ok(a) => a,
+            Err(e) => {
+                match include{
+                    Some(name) => println!("{}:{}:{} Couldn't read {}: {}", name.filename, name.row, name.col, source_filename, e),
+                    None => println!("{}: Couldn't read source file: {}", source_filename, e)
+                }
+                std::process::exit(1);
+            }
+        };
+
+        self.lex(source_filename, &content);
+    }
+}
@@ -1,5 +1,9 @@
 pub mod lexer;
+pub mod isa;
 pub mod instruction_lexer;
+pub mod isa_validator;
+pub mod isa_docs;
 pub mod pseudo_instructions;
 pub mod parser;
-pub mod codegen;
\ No newline at end of file
+pub mod codegen;
+pub mod byteoperations;
@@ -1,6 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
-use crate::{get_value_from_number_token, InstructionsLexer, Lexem, LexemType};
+use crate::{get_signed_value_from_number_token, is_local_reference, word_number, InstructionPart, InstructionsLexer, Lexem, LexemType, Lexer, PUNCTUATION_LEXEMS};
+
+// directives handled by the assembler itself, shared with ISA validation and docs
+pub const DIRECTIVES: &[&str] = &[
+    "org", "db", "dw", "dd", "dq", ".cpu", ".include", ".incbin",
+    "equ", "=", ".set",
+    ".macro", ".endm",
+    ".if", ".elif", ".else", ".endif", ".ifdef", ".ifndef",
+    ".rept", ".irp", ".irpc", ".endr",
+    ".scope", ".endscope", ".proc", ".endproc"
+];
+
+// directives that only define symbols or scopes and take no space
+pub const SYMBOL_DIRECTIVES: &[&str] = &[".cpu", "equ", "=", ".set", ".scope", ".endscope", ".proc", ".endproc"];
+
+// parameter names and body of pseudo instruction
+pub type PseudoInstruction = (Vec<String>, Vec<Token>);
+
+// tokens left after label discovery, label addresses and constant definitions (name, value)
+type DiscoveredLabels = (Vec<Token>, HashMap<String, usize>, Vec<(Lexem, Lexem)>);
 
 #[derive(Debug, Clone)]
 pub enum Token{
@@ -30,22 +50,8 @@ fn fix_sub_label(last_label: &String, args: Vec<Lexem>) -> Vec<Lexem>{
         match arg.ttype.clone() {
 
             LexemType::Closure { args } => {
-                let mut new_arg: Vec<Lexem> = Vec::new();
-
-                for arg in args{
-                    let arg = *arg;
-                    new_arg.push(arg);
-                }
-
-                let new_arg = fix_sub_label(last_label, new_arg);
-
-                let mut args: Vec<Box<Lexem>> = Vec::new();
-
-                for arg in new_arg{
-                    args.push(Box::new(arg));
-                }
-
-                new_args.push(Lexem::new(arg.value.clone(), LexemType::Closure { args: [args[0].clone(), args[1].clone(), args[2].clone()] }, arg.row, arg.col, arg.filename.clone()));
+                let args = fix_sub_label(last_label, args);
+                new_args.push(Lexem::new(arg.value.clone(), LexemType::Closure { args }, arg.row, arg.col, arg.filename.clone()));
             }
 
             LexemType::Ident => {
@@ -67,84 +73,427 @@ fn fix_sub_label(last_label: &String, args: Vec<Lexem>) -> Vec<Lexem>{
     new_args
 }
 
-fn eval_closure(arg: Lexem, args: [Box<Lexem>; 3]) -> Lexem{
-    
-    let lhs = *args[0].clone();
-    let lhs = match lhs.ttype.clone(){
+// symbols are renamed to names in map, references to `.set` symbols point to the definition in effect at that place
+fn rename_symbols(set_versions: &HashMap<String, String>, args: Vec<Lexem>) -> Vec<Lexem>{
+    let mut new_args: Vec<Lexem> = Vec::new();
+
+    for mut arg in args{
+        match arg.ttype.clone(){
+            LexemType::Closure { args } => {
+                arg.ttype = LexemType::Closure { args: rename_symbols(set_versions, args) };
+            }
+            LexemType::Ident => {
+                if let Some(version) = set_versions.get(&arg.value){
+                    arg.value = version.clone();
+                }
+            }
+            _ => {}
+        }
+
+        new_args.push(arg);
+    }
+
+    new_args
+}
+
+// numeric local label `1` or reference to one `1b` / `1f`
+fn is_local_label(name: &str) -> bool{
+    name.starts_with(|a: char| a.is_ascii_digit())
+}
+
+// `1b` / `1f` stays reference only when `1:` is defined in the same source, otherwise it's number (1010b)
+fn resolve_local_references(lexems: &mut [Lexem]){
+    let defined: HashSet<String> = lexems.iter().enumerate().filter(|(i, a)| {
+        a.ttype == LexemType::Number { radix: 10 } && lexems.get(i+1).is_some_and(|a| a.value == ":") && (*i == 0 || lexems[i-1].ttype == LexemType::NewLine)
+    }).map(|(_, a)| a.value.clone()).collect();
+
+    for lexem in lexems.iter_mut(){
+        if lexem.ttype == LexemType::Ident && is_local_reference(&lexem.value) && !defined.contains(&lexem.value[..lexem.value.len()-1]){
+            *lexem = word_number(&lexem.value, lexem.row, lexem.col, &lexem.filename);
+        }
+    }
+}
+
+// `1b` / `1f` becomes name of nearest definition of `1` before / after it,
+// seen is number of definitions before this place and total in whole source
+fn rename_local_reference(arg: &mut Lexem, seen: &HashMap<String, usize>, total: &HashMap<String, usize>){
+    match &mut arg.ttype{
         LexemType::Closure { args } => {
-            eval_closure( lhs, args)
+            for arg in args.iter_mut(){
+                rename_local_reference(arg, seen, total);
+            }
         }
-        _ => lhs
-    };
+        LexemType::Ident if is_local_label(&arg.value) => {
+            let (number, direction) = arg.value.split_at(arg.value.len()-1);
+            let before = seen.get(number).copied().unwrap_or(0);
 
-    match lhs.ttype{
-        LexemType::Number { .. } => {}
-        LexemType::Ident { .. } => {
-            println!("{}:{}:{} Use of undeclared label {}", lhs.filename, lhs.row, lhs.col, lhs.value);
-            std::process::exit(1);
+            let index = if direction == "b"{
+                before.checked_sub(1)
+            }else{
+                Some(before).filter(|a| *a < total.get(number).copied().unwrap_or(0))
+            };
+
+            match index{
+                Some(i) => arg.value = format!("{}@{}", number, i),
+                None => {
+                    println!("{}:{}:{} No local label {} {} this", arg.filename, arg.row, arg.col, number, if direction == "b" {"before"} else {"after"});
+                    std::process::exit(1);
+                }
+            }
         }
-        _ => {
-            println!("{}:{}:{} Expected Number got {}", lhs.filename, lhs.row, lhs.col, lhs.ttype);
-            std::process::exit(1);
+        _ => {}
+    }
+}
+
+// name used inside scope is looked up from innermost scope outward, then as global name
+fn resolve_scoped(arg: &mut Lexem, scopes: &[String], defined: &HashSet<String>){
+    match &mut arg.ttype{
+        LexemType::Closure { args } => {
+            for arg in args.iter_mut(){
+                resolve_scoped(arg, scopes, defined);
+            }
+        }
+        LexemType::Ident if !arg.value.starts_with(".") && !is_local_label(&arg.value) => {
+            for depth in (1..=scopes.len()).rev(){
+                let qualified = format!("{}.{}", scopes[..depth].join("."), arg.value);
+
+                if defined.contains(&qualified){
+                    arg.value = qualified;
+                    return;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// first symbol that is still left in expression
+fn first_symbol(arg: &Lexem) -> Option<Lexem>{
+    match &arg.ttype{
+        LexemType::Closure { args } => args.iter().find_map(first_symbol),
+        LexemType::Ident => Some(arg.clone()),
+        _ => None
+    }
+}
+
+// constants are (name, value), value can use labels and other constants defined anywhere,
+// returns value of every symbol that could be resolved and indices of constants that couldn't
+fn resolve_constants(labels: &HashMap<String, usize>, constants: &[(Lexem, Lexem)]) -> (HashMap<String, i64>, Vec<usize>){
+    let mut symbols: HashMap<String, i64> = labels.iter().map(|(name, address)| (name.clone(), *address as i64)).collect();
+    let mut unresolved: Vec<usize> = (0..constants.len()).collect();
+
+    loop{
+        let mut progress = false;
+
+        unresolved.retain(|i| {
+            let (name, value) = &constants[*i];
+            let value = Parser::fix_args(&symbols, &mut vec![value.clone()]).remove(0);
+
+            if first_symbol(&value).is_some(){
+                return true;
+            }
+
+            symbols.insert(name.value.clone(), eval_operand(&value));
+            progress = true;
+            false
+        });
+
+        if !progress{
+            break;
         }
     }
 
-    let rhs = *args[2].clone();
-    let rhs = match rhs.ttype.clone(){
+    (symbols, unresolved)
+}
+
+// value that has to be known at this point of the first pass (org address, .incbin offset),
+// it can use labels and constants defined before
+fn value_known_here(arg: &Lexem, what: &str, labels: &HashMap<String, usize>, constants: &[(Lexem, Lexem)]) -> usize{
+    let (symbols, _) = resolve_constants(labels, constants);
+    let arg = Parser::fix_args(&symbols, &mut vec![arg.clone()]).remove(0);
+
+    if let Some(symbol) = first_symbol(&arg){
+        println!("{}:{}:{} {} has to be known here, {} isn't", symbol.filename, symbol.row, symbol.col, what, symbol.value);
+        std::process::exit(1);
+    }
+
+    let val = eval_operand(&arg);
+    if val < 0{
+        println!("{}:{}:{} Expected unsigned number got {}", arg.filename, arg.row, arg.col, val);
+        std::process::exit(1);
+    }
+
+    val as usize
+}
+
+// `$` (also inside expressions) becomes label with current address
+fn replace_dollar(arg: &mut Lexem, labels: &mut HashMap<String, usize>, address: usize, dollar_signs: &mut usize){
+    match &mut arg.ttype{
         LexemType::Closure { args } => {
-            eval_closure(rhs, args)
+            for arg in args.iter_mut(){
+                replace_dollar(arg, labels, address, dollar_signs);
+            }
+        }
+        LexemType::Single if arg.value == "$" => {
+            let name = format!("${}", dollar_signs);
+            *arg = Lexem::new(name.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
+            labels.insert(name, address);
+            *dollar_signs += 1;
+        }
+        _ => {}
+    }
+}
+
+// expression that can be evaluated without knowing any addresses
+// arguments with symbols known so far (or guessed from previous pass) replaced and constant expressions folded,
+// used to pick form of overloaded instruction
+fn known_args(args: &[Lexem], last_label: &String, set_versions: &HashMap<String, String>, labels: &HashMap<String, usize>, constants: &[(Lexem, Lexem)], guess: &HashMap<String, i64>) -> Vec<Lexem>{
+    let (mut symbols, _) = resolve_constants(labels, constants);
+
+    for (name, value) in guess{
+        symbols.entry(name.clone()).or_insert(*value);
+    }
+
+    let mut args = rename_symbols(set_versions, fix_sub_label(last_label, args.to_vec()));
+
+    Parser::fix_args(&symbols, &mut args).into_iter().map(|a| match a.ttype{
+        LexemType::Closure { .. } if is_constant_expression(&a) => Lexem::new(eval_operand(&a).to_string(), LexemType::Number { radix: 10 }, a.row, a.col, a.filename.clone()),
+        _ => a
+    }).collect()
+}
+
+fn is_constant_expression(arg: &Lexem) -> bool{
+    match &arg.ttype{
+        LexemType::Closure { args } => args.iter().all(is_constant_expression),
+        LexemType::Ident => false,
+        _ => arg.value != "$"
+    }
+}
+
+// size of one item of data directive in bits
+pub fn data_bits(directive: &str) -> Option<usize>{
+    match directive.to_lowercase().as_str(){
+        "db" => Some(8),
+        "dw" => Some(16),
+        "dd" => Some(32),
+        "dq" => Some(64),
+        _ => None
+    }
+}
+
+// addresses taken by data directive arguments, `$` becomes label with address of the directive
+fn data_size(args: &mut [Lexem], item_size: usize, labels: &mut HashMap<String, usize>, address: usize, dollar_signs: &mut usize) -> usize{
+    let mut size = 0;
+
+    for arg in args.iter_mut(){
+        match arg.ttype{
+            LexemType::Ident => size += item_size,
+            LexemType::Closure { .. } => {
+                replace_dollar(arg, labels, address, dollar_signs);
+                size += item_size;
+            }
+            LexemType::String => size += arg.value.len()*item_size,
+            LexemType::Number { .. } => size += item_size,
+            _ => {
+                if arg.value == "$"{
+                    replace_dollar(arg, labels, address, dollar_signs);
+                    size += item_size;
+                    continue;
+                }
+                println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
+            }
         }
-        _ => rhs
+    }
+
+    size
+}
+
+// value of expression operand, nested expressions are evaluated first
+fn eval_operand(lexem: &Lexem) -> i64{
+    let lexem = match lexem.ttype.clone(){
+        LexemType::Closure { args } => eval_closure(lexem.clone(), args),
+        _ => lexem.clone()
     };
 
-    match rhs.ttype{
+    match lexem.ttype{
         LexemType::Number { .. } => {}
         LexemType::Ident { .. } => {
-            println!("{}:{}:{} Use of undeclared label {}", rhs.filename, rhs.row, rhs.col, rhs.value);
+            println!("{}:{}:{} Use of undeclared label {}", lexem.filename, lexem.row, lexem.col, lexem.value);
             std::process::exit(1);
         }
         _ => {
-            println!("{}:{}:{} Expected Number got {}", rhs.filename, rhs.row, rhs.col, rhs.ttype);
+            println!("{}:{}:{} Expected Number got {}", lexem.filename, lexem.row, lexem.col, lexem.ttype);
             std::process::exit(1);
         }
     }
-    
 
-    let lhs = get_value_from_number_token(&lhs);
-    let rhs = get_value_from_number_token(&rhs);
+    get_signed_value_from_number_token(&lexem)
+}
+
+// signed 64 bit arithmetic, overflow, division by zero and shifts out of range are errors
+fn eval_arithmetic(op: &Lexem, lhs: i64, rhs: i64) -> i64{
+    if (op.value == "/" || op.value == "%") && rhs == 0{
+        println!("{}:{}:{} Division by zero in {} {} {}", op.filename, op.row, op.col, lhs, op.value, rhs);
+        std::process::exit(1);
+    }
 
-    let op = *args[1].clone();
-    if op.ttype != LexemType::Operator{
-        println!("{}:{}:{} Expected Operator got {}", op.filename, op.row, op.col, op.ttype);
+    if (op.value == "<<" || op.value == ">>") && !(0..64).contains(&rhs){
+        println!("{}:{}:{} Shift by {} is out of range (0..=63)", op.filename, op.row, op.col, rhs);
         std::process::exit(1);
     }
 
-    let ret_val: usize;
+    let ret_val = match op.value.as_str(){
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" => lhs.checked_div(rhs),
+        "%" => lhs.checked_rem(rhs),
+        // bits shifted out have to be copies of sign bit
+        "<<" => Some(lhs << rhs).filter(|a| a >> rhs == lhs),
+        ">>" => Some(lhs >> rhs),
+        _ => unreachable!()
+    };
 
-    match op.value.as_str(){
-        "+" => {ret_val = lhs+rhs},
-        "-" => {ret_val = lhs-rhs},
-        "*" => {ret_val = lhs*rhs},
-        "/" => {ret_val = lhs/rhs},
-        "&" => {ret_val = lhs&rhs},
-        "|" => {ret_val = lhs|rhs},
-        "^" => {ret_val = lhs^rhs},
-        "<<" => {ret_val = lhs<<rhs},
-        ">>" => {ret_val = lhs>>rhs},
-        _ => {
-            println!("{}:{}:{} Invalid Operator {}",op.filename, op.row, op.col, op.value);
+    match ret_val{
+        Some(a) => a,
+        None => {
+            println!("{}:{}:{} Overflow in {} {} {}", op.filename, op.row, op.col, lhs, op.value, rhs);
             std::process::exit(1);
         }
     }
+}
+
+fn eval_closure(arg: Lexem, args: Vec<Lexem>) -> Lexem{
+    let ret_val: i64 = match args.len(){
+        // op operand
+        2 => {
+            let op = &args[0];
+            let val = eval_operand(&args[1]);
+
+            match op.value.as_str(){
+                "-" => match val.checked_neg(){
+                    Some(a) => a,
+                    None => {
+                        println!("{}:{}:{} Overflow in -({})", op.filename, op.row, op.col, val);
+                        std::process::exit(1);
+                    }
+                },
+                "~" => !val,
+                "!" => (val == 0) as i64,
+                _ => {
+                    println!("{}:{}:{} Invalid Operator {}",op.filename, op.row, op.col, op.value);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // lhs op rhs
+        3 => {
+            let op = &args[1];
+            if op.ttype != LexemType::Operator{
+                println!("{}:{}:{} Expected Operator got {}", op.filename, op.row, op.col, op.ttype);
+                std::process::exit(1);
+            }
+
+            let lhs = eval_operand(&args[0]);
+
+            // rhs of && and || is only evaluated when needed
+            match op.value.as_str(){
+                "&&" if lhs == 0 => 0,
+                "||" if lhs != 0 => 1,
+                "&&" | "||" => (eval_operand(&args[2]) != 0) as i64,
+                _ => {
+                    let rhs = eval_operand(&args[2]);
+
+                    match op.value.as_str(){
+                        "+" | "-" | "*" | "/" | "%" | "<<" | ">>" => eval_arithmetic(op, lhs, rhs),
+                        "&" => lhs&rhs,
+                        "|" => lhs|rhs,
+                        "^" => lhs^rhs,
+                        "==" => (lhs == rhs) as i64,
+                        "!=" => (lhs != rhs) as i64,
+                        "<" => (lhs < rhs) as i64,
+                        "<=" => (lhs <= rhs) as i64,
+                        ">" => (lhs > rhs) as i64,
+                        ">=" => (lhs >= rhs) as i64,
+                        _ => {
+                            println!("{}:{}:{} Invalid Operator {}",op.filename, op.row, op.col, op.value);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            }
+        }
+
+        // cond ? a : b, only picked branch is evaluated
+        5 => {
+            if eval_operand(&args[0]) != 0{
+                eval_operand(&args[2])
+            }else{
+                eval_operand(&args[4])
+            }
+        }
+
+        _ => {
+            println!("{}:{}:{} Invalid expression", arg.filename, arg.row, arg.col);
+            std::process::exit(1);
+        }
+    };
 
     Lexem::new(format!("{}", ret_val), LexemType::Number { radix: 10 }, arg.row, arg.col, arg.filename)
 }
 
+// C-like precedence of binary operators, higher binds tighter
+// unary operators bind tighter than all of them and `?:` looser
+fn binary_precedence(lexem: &Lexem) -> Option<usize>{
+    if lexem.ttype != LexemType::Operator{
+        return None;
+    }
+
+    match lexem.value.as_str(){
+        "*" | "/" | "%" => Some(10),
+        "+" | "-" => Some(9),
+        "<<" | ">>" => Some(8),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "==" | "!=" => Some(6),
+        "&" => Some(5),
+        "^" => Some(4),
+        "|" => Some(3),
+        "&&" => Some(2),
+        "||" => Some(1),
+        _ => None
+    }
+}
+
+// parameter of macro defined in source, built-in pseudo instructions only have plain parameters
+#[derive(Debug, Clone)]
+pub struct MacroParam{
+    name: String,
+    default: Option<Lexem>,
+    variadic: bool
+}
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// one `.if` ... `.endif` block, at most one branch is assembled
+struct Conditional{
+    start: Lexem,
+    parent_active: bool,
+    taken: bool,
+    active: bool,
+    seen_else: bool
+}
+
 pub struct Parser{
     cursor: usize,
     lexems: Vec<Lexem>,
     pub tokens: Vec<Token>,
     pseudo_instructions: Option<HashMap<String, (Vec<String>,Vec<Token>)>>,
+    // form of overloaded instruction picked when labels were discovered, by index in tokens
+    pub forms: HashMap<usize, usize>,
+    // searched for `.include` and `.incbin` files after directory of including file
+    pub include_dirs: Vec<String>,
+    pub dollar_hex: bool,
 }
 
 impl Parser{
@@ -153,7 +502,10 @@ impl Parser{
             cursor: 0,
             lexems: Vec::new(),
             tokens: Vec::new(),
-            pseudo_instructions
+            pseudo_instructions,
+            forms: HashMap::new(),
+            include_dirs: Vec::new(),
+            dollar_hex: false
         }
     }
 
@@ -194,12 +546,16 @@ impl Parser{
         }
 
 
-        if self.peek_lexem().unwrap().ttype != LexemType::Ident{
+        // numeric local label `1:` is kept as name
+        let is_local = self.peek_lexem().unwrap().ttype == LexemType::Number { radix: 10 };
+
+        if (self.peek_lexem().unwrap().ttype != LexemType::Ident || is_local_reference(&self.peek_lexem().unwrap().value)) && !is_local{
             self.cursor = initial_cursor;
             return false;
         }
 
-        let label_name = self.chop_lexem();
+        let mut label_name = self.chop_lexem();
+        label_name.ttype = LexemType::Ident;
 
         if self.cursor >= self.lexems.len(){
             self.cursor = initial_cursor;
@@ -218,30 +574,143 @@ impl Parser{
         return true;
     }
 
-    fn parse_arg(self: &mut Self) -> Lexem{
-        let lexem = self.chop_lexem();
+    // next lexem of expression, expression can't continue past end of line
+    fn chop_expression_lexem(self: &mut Self, expected: &str) -> Lexem{
+        match self.peek_lexem(){
+            Some(a) if a.ttype != LexemType::NewLine => self.chop_lexem(),
+            _ => {
+                let last = &self.lexems[self.cursor - 1];
+                println!("{}:{}:{} Expected {} got end of line", last.filename, last.row, last.col+last.value.len(), expected);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn parse_primary(self: &mut Self) -> Lexem{
+        let lexem = self.chop_expression_lexem("expression");
 
         if lexem.value == "("{
-            
-            
-            let lhs = Box::new(self.parse_arg());
-            let operator = Box::new(self.parse_arg());
-            let rhs = Box::new(self.parse_arg());
-            let lexem = Lexem::new("Closure".to_string(), LexemType::Closure { args: [lhs, operator, rhs] }, lexem.row, lexem.col, lexem.filename);
+            let expression = self.parse_expression(false);
 
-            let test = self.chop_lexem();
+            let test = self.chop_expression_lexem("\")\"");
 
             if test.value == ")"{
-                return lexem;
+                return expression;
             }else{
                 println!("{}:{}:{} Expected \")\" got \"{}\"", test.filename, test.row, test.col, test.value);
                 std::process::exit(1);
             }
+        }else if lexem.value == "-" && self.peek_lexem().is_some_and(|a| matches!(a.ttype, LexemType::Number { .. })){
+            let number = self.chop_lexem();
+            return Lexem::new(format!("-{}", number.value), number.ttype, lexem.row, lexem.col, lexem.filename);
+        }else if lexem.ttype == LexemType::Operator && ["-", "~", "!"].contains(&lexem.value.as_str()){
+            let operand = self.parse_primary();
+            let (row, col, filename) = (lexem.row, lexem.col, lexem.filename.clone());
+            return Lexem::new("Closure".to_string(), LexemType::Closure { args: vec![lexem, operand] }, row, col, filename);
+        }else if lexem.ttype == LexemType::Operator || (lexem.ttype == LexemType::Single && lexem.value != "$"){
+            println!("{}:{}:{} Expected expression got \"{}\"", lexem.filename, lexem.row, lexem.col, lexem.value);
+            std::process::exit(1);
         }else{
             return lexem;
         }
     }
 
+    // precedence climbing, operators of the same precedence are left associative
+    // inside operand template brackets `+` is punctuation unless in parentheses
+    fn parse_binary(self: &mut Self, min_precedence: usize, in_brackets: bool) -> Lexem{
+        let mut lhs = self.parse_primary();
+
+        while let Some(op) = self.peek_lexem(){
+            if in_brackets && op.value == "+"{
+                break;
+            }
+
+            let precedence = match binary_precedence(&op){
+                Some(a) if a >= min_precedence => a,
+                _ => break
+            };
+
+            let op = self.chop_lexem();
+            let rhs = self.parse_binary(precedence + 1, in_brackets);
+
+            let (row, col, filename) = (lhs.row, lhs.col, lhs.filename.clone());
+            lhs = Lexem::new("Closure".to_string(), LexemType::Closure { args: vec![lhs, op, rhs] }, row, col, filename);
+        }
+
+        lhs
+    }
+
+    // `cond ? a : b` is right associative, `:` is punctuation inside template brackets
+    // so there it needs parentheses
+    fn parse_expression(self: &mut Self, in_brackets: bool) -> Lexem{
+        let condition = self.parse_binary(0, in_brackets);
+
+        if in_brackets || !self.peek_lexem().is_some_and(|a| a.ttype == LexemType::Operator && a.value == "?"){
+            return condition;
+        }
+
+        let question = self.chop_lexem();
+        let then_val = self.parse_expression(false);
+
+        let colon = self.chop_expression_lexem("\":\"");
+        if colon.value != ":"{
+            println!("{}:{}:{} Expected \":\" got \"{}\"", colon.filename, colon.row, colon.col, colon.value);
+            std::process::exit(1);
+        }
+
+        let else_val = self.parse_expression(false);
+
+        let (row, col, filename) = (condition.row, condition.col, condition.filename.clone());
+        Lexem::new("Closure".to_string(), LexemType::Closure { args: vec![condition, question, then_val, colon, else_val] }, row, col, filename)
+    }
+
+    fn parse_arg(self: &mut Self, in_brackets: bool) -> Lexem{
+        self.parse_expression(in_brackets)
+    }
+
+    fn is_punctuation(self: &Self, depth: usize) -> bool{
+        match self.peek_lexem(){
+            Some(a) => {
+                (a.ttype == LexemType::Single && PUNCTUATION_LEXEMS.contains(&a.value.as_str()))
+                || (depth > 0 && a.ttype == LexemType::Operator && a.value == "+")
+            }
+            None => false
+        }
+    }
+
+    // operand templates like `st [seg:r6]` keep punctuation as separate args
+    fn parse_arg_group(self: &mut Self, args: &mut Vec<Lexem>){
+        let mut depth: usize = 0;
+        let mut expect_operand = true;
+
+        while self.cursor < self.lexems.len(){
+            let lexem = self.peek_lexem().unwrap();
+
+            if lexem.ttype == LexemType::NewLine || lexem.value == ","{
+                break;
+            }
+
+            if self.is_punctuation(depth){
+                let lexem = self.chop_lexem();
+                match lexem.value.as_str(){
+                    "[" => depth += 1,
+                    "]" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                args.push(lexem);
+                expect_operand = true;
+                continue;
+            }
+
+            if !expect_operand{
+                break;
+            }
+
+            args.push(self.parse_arg(depth > 0));
+            expect_operand = false;
+        }
+    }
+
     fn parse_args(self: &mut Self) -> Option<Vec<Lexem>>{
 
         let mut args: Vec<Lexem> = Vec::new();
@@ -257,7 +726,7 @@ impl Parser{
         }
 
 
-        args.push(self.parse_arg());
+        self.parse_arg_group(&mut args);
 
         if self.cursor >= self.lexems.len(){
             return Some(args);
@@ -271,7 +740,7 @@ impl Parser{
                 std::process::exit(1);
             }
 
-            args.push(self.parse_arg());
+            self.parse_arg_group(&mut args);
 
             if self.cursor < self.lexems.len() && self.peek_lexem().unwrap().ttype == LexemType::NewLine{
                 break;
@@ -282,6 +751,52 @@ impl Parser{
         return Some(args);
     }
 
+    // `.macro name a, b=default, rest...` becomes args [name, a, (b = default), rest...]
+    fn parse_macro_header(self: &mut Self, keyword: &Lexem) -> Vec<Lexem>{
+        let macro_name = match self.peek_lexem(){
+            Some(a) if a.ttype == LexemType::Ident => self.chop_lexem(),
+            _ => {
+                println!("{}:{}:{} Expected macro name", keyword.filename, keyword.row, keyword.col+keyword.value.len());
+                std::process::exit(1);
+            }
+        };
+
+        let mut args: Vec<Lexem> = vec![macro_name];
+
+        while let Some(param) = self.peek_lexem(){
+            if param.ttype == LexemType::NewLine{
+                break;
+            }
+
+            if param.ttype != LexemType::Ident{
+                println!("{}:{}:{} Expected parameter name got {}", param.filename, param.row, param.col, param.value);
+                std::process::exit(1);
+            }
+
+            let param = self.chop_lexem();
+
+            if self.peek_lexem().is_some_and(|a| a.ttype == LexemType::Operator && a.value == "="){
+                let op = self.chop_lexem();
+                let default = self.parse_arg(false);
+                let (row, col, filename) = (param.row, param.col, param.filename.clone());
+                args.push(Lexem::new("Closure".to_string(), LexemType::Closure { args: vec![param, op, default] }, row, col, filename));
+            }else{
+                args.push(param);
+            }
+
+            match self.peek_lexem(){
+                Some(a) if a.value == "," => {self.chop_lexem();}
+                Some(a) if a.ttype != LexemType::NewLine => {
+                    println!("{}:{}:{} Expected \",\" got {}", a.filename, a.row, a.col, a.value);
+                    std::process::exit(1);
+                }
+                _ => {}
+            }
+        }
+
+        args
+    }
+
     fn parse_lexem_instruction(self: &mut Self) -> bool{
 
         
@@ -301,6 +816,25 @@ impl Parser{
 
         let name = self.chop_lexem();
 
+        if name.value.to_lowercase() == ".macro"{
+            let args = self.parse_macro_header(&name);
+            self.tokens.push(Token::Instruction { name, args });
+            return true;
+        }
+
+        // `NAME equ expr` and `NAME = expr` become `equ NAME, expr` and `= NAME, expr`
+        if let Some(keyword) = self.peek_lexem(){
+            if (keyword.ttype == LexemType::Ident && keyword.value.to_lowercase() == "equ") || (keyword.ttype == LexemType::Operator && keyword.value == "="){
+                let mut keyword = self.chop_lexem();
+                keyword.value = keyword.value.to_lowercase();
+
+                let value = self.parse_arg(false);
+
+                self.tokens.push(Token::Instruction { name: keyword, args: vec![name, value] });
+                return true;
+            }
+        }
+
         let args = match self.parse_args(){
             Some(a) => a,
             None => {
@@ -336,6 +870,8 @@ impl Parser{
         self.lexems = lexems.clone();
         self.cursor = 0;
 
+        resolve_local_references(&mut self.lexems);
+
         self.tokens.clear();
         
         while self.cursor < self.lexems.len(){
@@ -343,7 +879,8 @@ impl Parser{
         }
     }
 
-    fn discover_labels(self: &mut Self, instruction_lexer: &InstructionsLexer) -> (Vec<Token>, HashMap<String, usize>) {
+    // guess holds symbol values from previous pass and previous the forms it picked
+    fn discover_labels(self: &mut Self, instruction_lexer: &InstructionsLexer, guess: &HashMap<String, i64>, previous: &HashMap<usize, usize>) -> DiscoveredLabels {
         let mut origin: usize = 0;
         self.cursor = 0;
 
@@ -351,9 +888,16 @@ impl Parser{
 
         let mut labels: HashMap<String, usize> = HashMap::new();
 
+        // equ / = / .set definitions, every `.set` gets its own version `NAME@n`
+        let mut constants: Vec<(Lexem, Lexem)> = Vec::new();
+        let mut set_versions: HashMap<String, String> = HashMap::new();
+
         let mut last_label = String::new();
 
         let mut dollar_signs = 0;
+
+        // form picked for instruction at index in cleaned tokens
+        let mut forms: HashMap<usize, usize> = HashMap::new();
         
         for token in self.tokens.iter_mut(){
             match token{
@@ -368,146 +912,142 @@ impl Parser{
                                 std::process::exit(1);
                             }
 
-                            let arg = args[0].clone();
-                            if !matches!(arg.ttype, LexemType::Number { .. }){
-                                println!("{}:{}:{} Expected number got {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                std::process::exit(1);
-                            }
+                            let arg = fix_sub_label(&last_label, rename_symbols(&set_versions, args.clone())).remove(0);
 
-                            origin = get_value_from_number_token(&arg);
+                            origin = value_known_here(&arg, "Address of org", &labels, &constants);
                             self.cursor = 0;
                         }
 
-                        "db" => {
-                        
-                            let mut to_add = 0;
-    
-                            for arg in args.iter_mut(){
-                                match arg.ttype{
-                                    LexemType::Ident => to_add += 1,
-                                    LexemType::String => to_add += arg.value.len(),
-                                    LexemType::Number { .. } => to_add += 1,
-                                    _ => {
-                                        if arg.value == "$"{
-                                            let name = format!("${}",dollar_signs);
-                                            let new_lexem = Lexem::new(name.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
-                                            *arg = new_lexem;
-                                            labels.insert(name, origin+self.cursor);
-                                            to_add += 1;
-                                            dollar_signs += 1;
-                                            continue;
-                                        }
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    }
-                                }
+                        ".incbin" => {
+                            if args.is_empty() || args.len() > 3 || args[0].ttype != LexemType::String{
+                                println!("{}:{}:{} Expected \"file\"[, offset[, length]]", name.filename, name.row, name.col+name.value.len());
+                                std::process::exit(1);
                             }
-    
-                            cleaned_tokens.push(Token::Instruction { name, args: args.to_vec() });
-                            self.cursor += to_add;
-                        }
 
-                        "dw" => {
-                        
-                            let mut to_add = 0;
-    
-                            for arg in args.iter_mut(){
-                                match arg.ttype{
-                                    LexemType::Ident => to_add += 2,
-                                    LexemType::String => to_add += arg.value.len()*2,
-                                    LexemType::Number { .. } => to_add += 2,
-                                    _ => {
-                                        if arg.value == "$"{
-                                            let name = format!("${}",dollar_signs);
-                                            let new_lexem = Lexem::new(name.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
-                                            *arg = new_lexem;
-                                            labels.insert(name, origin+self.cursor);
-                                            to_add += 2;
-                                            dollar_signs += 1;
-                                            continue;
-                                        }
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    }
-                                }
-                            }
-    
-                            cleaned_tokens.push(Token::Instruction { name, args: args.to_vec() });
-                            self.cursor += to_add;
-                        }
+                            // binary files are looked up the same way as includes
+                            let mut file = args[0].clone();
+                            file.value = Lexer::find_include(&file.filename, &file, &self.include_dirs);
 
-                        "dd" => {
-                        
-                            let mut to_add = 0;
-    
-                            for arg in args.iter_mut(){
-                                match arg.ttype{
-                                    LexemType::Ident => to_add += 4,
-                                    LexemType::String => to_add += arg.value.len()*4,
-                                    LexemType::Number { .. } => to_add += 4,
-                                    _ => {
-                                        if arg.value == "$"{
-                                            let name = format!("${}",dollar_signs);
-                                            let new_lexem = Lexem::new(name.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
-                                            *arg = new_lexem;
-                                            labels.insert(name, origin+self.cursor);
-                                            to_add += 4;
-                                            dollar_signs += 1;
-                                            continue;
-                                        }
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    }
+                            let size = match std::fs::metadata(&file.value){
+                                Ok(a) => a.len() as usize,
+                                Err(e) => {
+                                    println!("{}:{}:{} Couldn't read {}: {}", file.filename, file.row, file.col, file.value, e);
+                                    std::process::exit(1);
                                 }
+                            };
+
+                            let args = fix_sub_label(&last_label, rename_symbols(&set_versions, args.clone()));
+
+                            let offset = match args.get(1){
+                                Some(a) => value_known_here(a, "Offset of .incbin", &labels, &constants),
+                                None => 0
+                            };
+
+                            if offset > size{
+                                println!("{}:{}:{} Offset {} is past the end of {} ({} bytes)", args[1].filename, args[1].row, args[1].col, offset, file.value, size);
+                                std::process::exit(1);
                             }
-    
-                            cleaned_tokens.push(Token::Instruction { name, args: args.to_vec() });
-                            self.cursor += to_add;
-                        }
 
-                        "dq" => {
-                        
-                            let mut to_add = 0;
-    
-                            for arg in args.iter_mut(){
-                                match arg.ttype{
-                                    LexemType::Ident => to_add += 8,
-                                    LexemType::String => to_add += arg.value.len()*8,
-                                    LexemType::Number { .. } => to_add += 8,
-                                    _ => {
-                                        if arg.value == "$"{
-                                            let name = format!("${}",dollar_signs);
-                                            let new_lexem = Lexem::new(name.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
-                                            *arg = new_lexem;
-                                            labels.insert(name, origin+self.cursor);
-                                            to_add += 8;
-                                            dollar_signs += 1;
-                                            continue;
-                                        }
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                    }
-                                }
+                            let length = match args.get(2){
+                                Some(a) => value_known_here(a, "Length of .incbin", &labels, &constants),
+                                None => size - offset
+                            };
+
+                            if offset + length > size{
+                                println!("{}:{}:{} Length {} from offset {} is past the end of {} ({} bytes)", args[2].filename, args[2].row, args[2].col, length, offset, file.value, size);
+                                std::process::exit(1);
                             }
-    
-                            cleaned_tokens.push(Token::Instruction { name, args: args.to_vec() });
-                            self.cursor += to_add;
+
+                            let offset_lexem = Lexem::new(offset.to_string(), LexemType::Number { radix: 10 }, file.row, file.col, file.filename.clone());
+                            let length_lexem = Lexem::new(length.to_string(), LexemType::Number { radix: 10 }, file.row, file.col, file.filename.clone());
+                            let name = Lexem::new(".incbin".to_string(), name.ttype.clone(), name.row, name.col, name.filename.clone());
+
+                            cleaned_tokens.push(Token::Instruction { name, args: vec![file, offset_lexem, length_lexem] });
+
+                            // bytes are copied verbatim, last address is padded with zeros
+                            self.cursor += instruction_lexer.byte_units(length);
                         }
 
-                        _ => {
-                            let instruciton_size = instruction_lexer.get_instruction_size(&name.value);
-                            if instruciton_size == usize::MAX{
-                                eprintln!("{}:{}:{} Instruction `{}` doesn't exist", name.filename, name.row, name.col,&name.value);
+                        "equ" | "=" | ".set" => {
+                            if args.len() != 2 || args[0].ttype != LexemType::Ident{
+                                println!("{}:{}:{} Expected symbol name and value", name.filename, name.row, name.col);
                                 std::process::exit(1);
                             }
 
+                            let mut symbol = args[0].clone();
+                            if symbol.value.starts_with("."){
+                                symbol.value = last_label.clone() + symbol.value.as_str();
+                            }
+
+                            let mut value = args[1].clone();
+                            replace_dollar(&mut value, &mut labels, origin+self.cursor, &mut dollar_signs);
+                            let value = fix_sub_label(&last_label, rename_symbols(&set_versions, vec![value])).remove(0);
+
+                            let redefinable = name.value != "equ";
+                            let defined_as_set = set_versions.contains_key(&symbol.value);
+
+                            if labels.contains_key(&symbol.value) || constants.iter().any(|(a, _)| a.value == symbol.value) || (defined_as_set && !redefinable){
+                                println!("{}:{}:{} Label already defined {}", symbol.filename, symbol.row, symbol.col, symbol.value);
+                                std::process::exit(1);
+                            }
+
+                            if redefinable{
+                                let version = format!("{}@{}", symbol.value, constants.len());
+                                set_versions.insert(symbol.value.clone(), version.clone());
+                                symbol.value = version;
+                            }
+
+                            constants.push((symbol, value));
+                        }
+
+                        // ISA is selected before parsing
+                        ".cpu" => {}
+
+                        "db" | "dw" | "dd" | "dq" => {
+                            let item_size = instruction_lexer.data_units(data_bits(&name.value).unwrap());
+                            let to_add = data_size(args, item_size, &mut labels, origin+self.cursor, &mut dollar_signs);
+
+                            cleaned_tokens.push(Token::Instruction { name, args: rename_symbols(&set_versions, fix_sub_label(&last_label, args.to_vec())) });
+                            self.cursor += to_add;
+                        }
+
+                        _ => {
+                            let known = match instruction_lexer.instructions.get(&name.value){
+                                Some(a) if a.len() > 1 => known_args(args, &last_label, &set_versions, &labels, &constants, guess),
+                                _ => args.clone()
+                            };
+
+                            let form = instruction_lexer.select_form(&name, &known, origin+self.cursor, previous.get(&cleaned_tokens.len()).copied());
+                            let instruction = &instruction_lexer.instructions[&name.value][form];
+                            let instruciton_size = instruction_lexer.instruction_units(instruction);
+
                             for arg in args.iter_mut() {
-                                if arg.value == "$"{
-                                    let name = format!("${}",dollar_signs);
-                                    let new_lexem = Lexem::new(name.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
-                                    *arg = new_lexem;
-                                    labels.insert(name, origin+self.cursor);
-                                    dollar_signs += 1;
-                                }
+                                replace_dollar(arg, &mut labels, origin+self.cursor, &mut dollar_signs);
+                            }
+
+                            // pc relative operands become (target - address of this instruction)
+                            let positions = instruction.match_pattern(args).unwrap();
+
+                            let relative_args: Vec<usize> = instruction.operands.iter()
+                                .zip(positions.iter())
+                                .filter(|(a, _)| matches!(a, InstructionPart::Rel { .. }))
+                                .map(|(_, i)| *i)
+                                .collect();
+
+                            for i in relative_args{
+
+                                let arg = args[i].clone();
+                                let pc_name = format!("${}",dollar_signs);
+                                labels.insert(pc_name.clone(), origin+self.cursor);
+                                dollar_signs += 1;
+
+                                let op = Lexem::new("-".to_string(), LexemType::Operator, arg.row, arg.col, arg.filename.clone());
+                                let pc = Lexem::new(pc_name, LexemType::Ident, arg.row, arg.col, arg.filename.clone());
+                                args[i] = Lexem::new("Closure".to_string(), LexemType::Closure { args: vec![arg.clone(), op, pc] }, arg.row, arg.col, arg.filename);
                             }
                             
-                            cleaned_tokens.push( Token::Instruction{ name, args: fix_sub_label(&last_label, args.clone())});
+                            forms.insert(cleaned_tokens.len(), form);
+                            cleaned_tokens.push( Token::Instruction{ name, args: rename_symbols(&set_versions, fix_sub_label(&last_label, args.clone()))});
                             self.cursor += instruciton_size;
                         }
 
@@ -515,7 +1055,19 @@ impl Parser{
                 }
                 Token::Label { name } => {
 
-                    match labels.get(&name.value) {
+                    let full_name = if name.value.starts_with("."){
+                        last_label.clone()+name.value.as_str()
+                    }else{
+                        name.value.clone()
+                    };
+
+                    let is_constant = constants.iter().any(|(a, _)| a.value == full_name) || set_versions.contains_key(&full_name);
+
+                    match labels.get(&full_name) {
+                        _ if is_constant => {
+                            println!("{}:{}:{} Label already defined {}", name.filename, name.row, name.col, name.value);
+                            std::process::exit(1)
+                        }
                         Some(_) => {
                             println!("{}:{}:{} Label already defined {}", name.filename, name.row, name.col, name.value);
                             std::process::exit(1)
@@ -524,6 +1076,9 @@ impl Parser{
 
                             if name.value.starts_with("."){
                                 labels.insert(last_label.clone()+name.value.as_str(), origin+self.cursor);
+                            }else if is_local_label(&name.value) || name.value.contains('@'){
+                                // numeric and per-expansion labels don't change scope of `.sub` labels
+                                labels.insert(name.value.clone(), origin+self.cursor);
                             }else{
                                 last_label = name.value.clone();
                                 labels.insert(last_label.clone(), origin+self.cursor);
@@ -536,30 +1091,27 @@ impl Parser{
             }
         }
 
-        (cleaned_tokens, labels)
+        // `.set` symbol used before its first definition gets its final value
+        for (name, version) in set_versions{
+            let mut symbol = constants.iter().find(|(a, _)| a.value == version).unwrap().0.clone();
+            let value = Lexem::new(version, LexemType::Ident, symbol.row, symbol.col, symbol.filename.clone());
+            symbol.value = name;
+            constants.push((symbol, value));
+        }
+
+        self.forms = forms;
+
+        (cleaned_tokens, labels, constants)
     }
 
-    fn fix_args(self: &mut Self, labels: &HashMap<String, usize>, args: &mut Vec<Lexem>) -> Vec<Lexem>{
+    fn fix_args(labels: &HashMap<String, i64>, args: &mut Vec<Lexem>) -> Vec<Lexem>{
         let mut new_args: Vec<Lexem> = Vec::new();
         for arg in args{
             match arg.ttype.clone() {
 
-                LexemType::Closure { args } =>{
-                    
-                    let mut nargs = Vec::new();
-                    
-                    for arg in args{
-                        nargs.push(*arg);
-                    }
-                    let args = self.fix_args(labels, &mut nargs);
-
-                    let mut nargs = Vec::new();
-
-                    for arg in args{
-                        nargs.push(Box::new(arg));
-                    }
-
-                    new_args.push(Lexem::new(arg.value.clone(), LexemType::Closure { args: [nargs[0].clone(),nargs[1].clone(),nargs[2].clone()] }, arg.row, arg.col, arg.filename.clone()));
+                LexemType::Closure { mut args } =>{
+                    let args = Self::fix_args(labels, &mut args);
+                    new_args.push(Lexem::new(arg.value.clone(), LexemType::Closure { args }, arg.row, arg.col, arg.filename.clone()));
                 }
 
                 LexemType::Ident =>{
@@ -585,14 +1137,43 @@ impl Parser{
     }
 
     fn calculate_labels(self: &mut Self, instruction_lexer: &InstructionsLexer){
-        let (mut cleaned_tokens, labels) = self.discover_labels(instruction_lexer);
+        // forms of overloaded instructions are picked again with addresses from previous pass until they stop changing
+        let tokens = self.tokens.clone();
+        let mut guess: HashMap<String, i64> = HashMap::new();
+        let mut previous: Option<HashMap<usize, usize>> = None;
+
+        let (mut cleaned_tokens, labels, constants) = loop{
+            self.tokens = tokens.clone();
+            let discovered = self.discover_labels(instruction_lexer, &guess, previous.as_ref().unwrap_or(&HashMap::new()));
+
+            if previous.as_ref() == Some(&self.forms){
+                break discovered;
+            }
+
+            guess = resolve_constants(&discovered.1, &discovered.2).0;
+            previous = Some(self.forms.clone());
+        };
+
+        let (labels, unresolved) = resolve_constants(&labels, &constants);
+
+        if let Some(&i) = unresolved.first(){
+            let (name, value) = &constants[i];
+            let symbol = first_symbol(&Self::fix_args(&labels, &mut vec![value.clone()])[0]).unwrap();
+
+            if constants.iter().any(|(a, _)| a.value == symbol.value){
+                println!("{}:{}:{} Definition of {} is circular", name.filename, name.row, name.col, name.value.split('@').next().unwrap());
+            }else{
+                println!("{}:{}:{} Use of undeclared label {}", symbol.filename, symbol.row, symbol.col, symbol.value);
+            }
+            std::process::exit(1);
+        }
 
         dbg!(&labels);
 
         for arg in cleaned_tokens.iter_mut(){
             match arg{
                 Token::Instruction { name: _, args } =>{
-                    *args = self.fix_args(&labels, args);
+                    *args = Self::fix_args(&labels, args);
                 }
                 Token::Label { .. } => {
                     println!("Internal error labels shouldve been removed in this stage");
@@ -645,9 +1226,6 @@ impl Parser{
         match &mut arg.ttype{
 
             LexemType::Closure { args } => {
-                if &arg.value == arg_in{
-                    arg.value = replace_with.value.clone();
-                }
                 for arg in args{
                     Self::replace_lexem_with_val(arg, arg_in, replace_with);
                 }
@@ -676,33 +1254,129 @@ impl Parser{
                     }
                 }
 
-                _ => {}
+                // label named by parameter
+                Token::Label { name } => {
+                    if &name.value == arg_in{
+                        name.value = replace_with.value.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    // variadic parameter used as an argument is replaced by all remaining arguments
+    fn replace_variadic_arg(arg_in: &String, replace_with: &[Lexem], tokens: &mut [Token]){
+        for token in tokens.iter_mut(){
+            if let Token::Instruction { name: _, args } = token{
+                let mut new_args: Vec<Lexem> = Vec::new();
+
+                for arg in args.iter(){
+                    if arg.ttype == LexemType::Ident && &arg.value == arg_in{
+                        new_args.extend_from_slice(replace_with);
+                    }else{
+                        new_args.push(arg.clone());
+                    }
+                }
+
+                *args = new_args;
+            }
+        }
+    }
+
+    // labels defined in body get unique name `loop@n` for every expansion so body can be used many times,
+    // labels named by parameter and numeric local labels are left alone
+    fn rename_body_labels(params: &[MacroParam], body: &mut [Token], expansion: usize){
+        let renames: HashMap<String, String> = body.iter().filter_map(|a| match a{
+            Token::Label { name } if !is_local_label(&name.value) && !params.iter().any(|a| a.name == name.value) => {
+                Some((name.value.clone(), format!("{}@{}", name.value, expansion)))
+            }
+            _ => None
+        }).collect();
+
+        if renames.is_empty(){
+            return;
+        }
+
+        for token in body.iter_mut(){
+            match token{
+                Token::Label { name } => {
+                    if let Some(renamed) = renames.get(&name.value){
+                        name.value = renamed.clone();
+                    }
+                }
+                Token::Instruction { name: _, args } => {
+                    *args = rename_symbols(&renames, args.clone());
+                }
             }
         }
     }
 
-    pub fn expand_block(pseudo_instructions: &HashMap<String, (Vec<String>,Vec<Token>)>, tokens: &Vec<Token>) -> Vec<Token>{
+    pub fn expand_block(pseudo_instructions: &HashMap<String, (Vec<String>,Vec<Token>)>, macro_params: &HashMap<String, Vec<MacroParam>>, tokens: &Vec<Token>, depth: usize, expansions: &mut usize) -> Vec<Token>{
         let mut new_tokens: Vec<Token> = Vec::new();
 
         for token in tokens{
-            new_tokens.append(&mut Self::expand_pseudo_instruction(pseudo_instructions, &token));
+            new_tokens.append(&mut Self::expand_pseudo_instruction(pseudo_instructions, macro_params, &token, depth, expansions));
         }
 
         return new_tokens;
     }
 
-    fn expand_pseudo_instruction(pseudo_instructions: &HashMap<String, (Vec<String>,Vec<Token>)>, token: &Token) -> Vec<Token>{
+    fn expand_pseudo_instruction(pseudo_instructions: &HashMap<String, (Vec<String>,Vec<Token>)>, macro_params: &HashMap<String, Vec<MacroParam>>, token: &Token, depth: usize, expansions: &mut usize) -> Vec<Token>{
         match token{
             Token::Label { .. } => return vec![token.clone()],
             Token::Instruction { name, args } => {
                 match pseudo_instructions.get(&name.value){
                     Some(a) => {
+                        if depth > MAX_EXPANSION_DEPTH{
+                            println!("{}:{}:{} Expansion of {} is nested too deep (recursive macro?)", name.filename, name.row, name.col, name.value);
+                            std::process::exit(1);
+                        }
+
+                        // built-in pseudo instructions only have plain parameters
+                        let params: Vec<MacroParam> = match macro_params.get(&name.value){
+                            Some(a) => a.clone(),
+                            None => a.0.iter().map(|a| MacroParam { name: a.clone(), default: None, variadic: false }).collect()
+                        };
+
+                        let required = params.iter().filter(|a| a.default.is_none() && !a.variadic).count();
+                        let variadic = params.last().is_some_and(|a| a.variadic);
+
+                        if args.len() < required || (!variadic && args.len() > params.len()){
+                            let expected = match (variadic, required == params.len()){
+                                (true, _) => format!("at least {}", required),
+                                (false, true) => required.to_string(),
+                                (false, false) => format!("{}..{}", required, params.len())
+                            };
+                            println!("{}:{}:{} {} expects {} arguments got {}", name.filename, name.row, name.col, name.value, expected, args.len());
+                            std::process::exit(1);
+                        }
+
                         let mut vec = a.1.clone();
-                        for (i, arg) in a.0.iter().enumerate(){
-                            Self::replace_temp_arg_with_val(arg, &mut args[i].clone(), &mut vec);
+                        Self::rename_body_labels(&params, &mut vec, *expansions);
+                        *expansions += 1;
+
+                        // parameters are first renamed so values can't be mistaken for other parameters
+                        for (i, param) in params.iter().enumerate(){
+                            let mut placeholder = Lexem::new(format!("@arg{}", i), LexemType::Ident, name.row, name.col, name.filename.clone());
+                            Self::replace_temp_arg_with_val(&param.name, &mut placeholder, &mut vec);
+                        }
+
+                        for (i, param) in params.iter().enumerate(){
+                            let placeholder = format!("@arg{}", i);
+
+                            if param.variadic{
+                                Self::replace_variadic_arg(&placeholder, args.get(i..).unwrap_or(&[]), &mut vec);
+                                continue;
+                            }
+
+                            let mut value = match args.get(i){
+                                Some(a) => a.clone(),
+                                None => param.default.clone().unwrap()
+                            };
+                            Self::replace_temp_arg_with_val(&placeholder, &mut value, &mut vec);
                         }
 
-                        return Self::expand_block(pseudo_instructions, &vec);
+                        return Self::expand_block(pseudo_instructions, macro_params, &vec, depth + 1, expansions);
                     }
                     None => return vec![token.clone()]
                 }
@@ -710,9 +1384,563 @@ impl Parser{
         }
     }
 
-    pub fn expand_pseudo_instructions(self: &mut Self){
+    // `.macro` ... `.endm` blocks are removed from tokens and added to pseudo instructions
+    fn collect_macros(self: &mut Self, pseudo_instructions: &mut HashMap<String, (Vec<String>,Vec<Token>)>, instruction_lexer: &InstructionsLexer) -> HashMap<String, Vec<MacroParam>>{
+        let mut macro_params: HashMap<String, Vec<MacroParam>> = HashMap::new();
+        let mut tokens: Vec<Token> = Vec::new();
+
+        let mut definition: Option<(Lexem, Vec<MacroParam>, Vec<Token>)> = None;
+
+        for token in self.tokens.iter(){
+            let keyword = match token{
+                Token::Instruction { name, .. } => name.value.to_lowercase(),
+                Token::Label { .. } => String::new()
+            };
+
+            match (keyword.as_str(), &mut definition){
+                (".macro", Some(_)) => {
+                    let Token::Instruction { name, .. } = token else { unreachable!() };
+                    println!("{}:{}:{} Macro can't be defined inside another macro", name.filename, name.row, name.col);
+                    std::process::exit(1);
+                }
+
+                (".macro", None) => {
+                    let Token::Instruction { name: _, args } = token else { unreachable!() };
+
+                    let mut params: Vec<MacroParam> = Vec::new();
+
+                    for (i, arg) in args[1..].iter().enumerate(){
+                        let param = match &arg.ttype{
+                            LexemType::Closure { args } => MacroParam { name: args[0].value.clone(), default: Some(args[2].clone()), variadic: false },
+                            _ if arg.value.ends_with("...") => {
+                                if i != args.len() - 2{
+                                    println!("{}:{}:{} Only last parameter can be variadic", arg.filename, arg.row, arg.col);
+                                    std::process::exit(1);
+                                }
+                                MacroParam { name: arg.value.trim_end_matches("...").to_string(), default: None, variadic: true }
+                            }
+                            _ => MacroParam { name: arg.value.clone(), default: None, variadic: false }
+                        };
+
+                        if params.iter().any(|a| a.name == param.name){
+                            println!("{}:{}:{} Parameter {} is already defined", arg.filename, arg.row, arg.col, param.name);
+                            std::process::exit(1);
+                        }
+
+                        params.push(param);
+                    }
+
+                    definition = Some((args[0].clone(), params, Vec::new()));
+                }
+
+                (".endm", Some(_)) => {
+                    let (macro_name, params, body) = definition.take().unwrap();
+
+                    if macro_params.contains_key(&macro_name.value){
+                        println!("{}:{}:{} Macro {} is already defined", macro_name.filename, macro_name.row, macro_name.col, macro_name.value);
+                        std::process::exit(1);
+                    }
+
+                    if DIRECTIVES.contains(&macro_name.value.to_lowercase().as_str()){
+                        println!("{}:{}:{} Macro can't be named like directive {}", macro_name.filename, macro_name.row, macro_name.col, macro_name.value);
+                        std::process::exit(1);
+                    }
+
+                    if instruction_lexer.instructions.contains_key(&macro_name.value){
+                        println!("{}:{}:{} Macro can't be named like instruction {}", macro_name.filename, macro_name.row, macro_name.col, macro_name.value);
+                        std::process::exit(1);
+                    }
+
+                    if pseudo_instructions.contains_key(&macro_name.value){
+                        println!("Warning: {}:{}:{} macro {} shadows built-in pseudo instruction", macro_name.filename, macro_name.row, macro_name.col, macro_name.value);
+                    }
+
+                    pseudo_instructions.insert(macro_name.value.clone(), (params.iter().map(|a| a.name.clone()).collect(), body));
+                    macro_params.insert(macro_name.value.clone(), params);
+                }
+
+                (".endm", None) => {
+                    let Token::Instruction { name, .. } = token else { unreachable!() };
+                    println!("{}:{}:{} .endm without .macro", name.filename, name.row, name.col);
+                    std::process::exit(1);
+                }
+
+                (_, Some((_, _, body))) => body.push(token.clone()),
+
+                (_, None) => tokens.push(token.clone())
+            }
+        }
+
+        if let Some((macro_name, _, _)) = definition{
+            println!("{}:{}:{} Macro {} is missing .endm", macro_name.filename, macro_name.row, macro_name.col, macro_name.value);
+            std::process::exit(1);
+        }
+
+        self.tokens = tokens;
+
+        macro_params
+    }
+
+    // value that has to be known before labels are discovered, only constants defined before can be used
+    fn known_value(arg: &Lexem, what: &str, symbols: &HashMap<String, i64>, last_label: &String) -> i64{
+        let arg = Self::fix_args(symbols, &mut fix_sub_label(last_label, vec![arg.clone()])).remove(0);
+
+        if !is_constant_expression(&arg){
+            let symbol = first_symbol(&arg).unwrap_or(arg.clone());
+            println!("{}:{}:{} {} has to be known here, {} isn't", symbol.filename, symbol.row, symbol.col, what, symbol.value);
+            std::process::exit(1);
+        }
+
+        eval_operand(&arg)
+    }
+
+    // `.if` / `.elif` condition can only use constants defined before it, `.ifdef` / `.ifndef` also sees labels defined before
+    fn condition(name: &Lexem, args: &[Lexem], symbols: &HashMap<String, i64>, defined: &HashSet<String>, last_label: &String) -> bool{
+        let keyword = name.value.to_lowercase();
+
+        if args.len() != 1{
+            println!("{}:{}:{} {} expects one argument", name.filename, name.row, name.col, name.value);
+            std::process::exit(1);
+        }
+
+        if keyword == ".ifdef" || keyword == ".ifndef"{
+            let arg = fix_sub_label(last_label, args.to_vec()).remove(0);
+
+            if arg.ttype != LexemType::Ident{
+                println!("{}:{}:{} Expected symbol name got {}", arg.filename, arg.row, arg.col, arg.value);
+                std::process::exit(1);
+            }
+
+            return defined.contains(&arg.value) == (keyword == ".ifdef");
+        }
+
+        Self::known_value(&args[0], "Condition", symbols, last_label) != 0
+    }
+
+    // `.rept count[, counter]`, `.irp symbol[:counter], values...` and `.irpc symbol[:counter], "string"`
+    // return (symbol, counter, values), `.rept` has no symbol
+    fn repeat_header(name: &Lexem, args: &[Lexem], symbols: &HashMap<String, i64>, last_label: &String) -> (Option<String>, Option<String>, Vec<Lexem>){
+        let keyword = name.value.to_lowercase();
+
+        let expect_symbol = |arg: Option<&Lexem>| -> String{
+            match arg{
+                Some(a) if a.ttype == LexemType::Ident => a.value.clone(),
+                Some(a) => {
+                    println!("{}:{}:{} Expected symbol name got {}", a.filename, a.row, a.col, a.value);
+                    std::process::exit(1);
+                }
+                None => {
+                    println!("{}:{}:{} {} expects symbol name", name.filename, name.row, name.col, name.value);
+                    std::process::exit(1);
+                }
+            }
+        };
+
+        if keyword == ".rept"{
+            if args.is_empty() || args.len() > 2{
+                println!("{}:{}:{} Expected count[, counter]", name.filename, name.row, name.col+name.value.len());
+                std::process::exit(1);
+            }
+
+            let count = Self::known_value(&args[0], "Repeat count", symbols, last_label);
+            if count < 0{
+                println!("{}:{}:{} Repeat count can't be negative got {}", args[0].filename, args[0].row, args[0].col, count);
+                std::process::exit(1);
+            }
+
+            let counter = args.get(1).map(|a| expect_symbol(Some(a)));
+            let values = (0..count).map(|i| Lexem::new(i.to_string(), LexemType::Number { radix: 10 }, name.row, name.col, name.filename.clone())).collect();
+
+            return (None, counter, values);
+        }
+
+        let symbol = expect_symbol(args.first());
+
+        let (counter, values) = if args.get(1).is_some_and(|a| a.value == ":"){
+            (Some(expect_symbol(args.get(2))), &args[3.min(args.len())..])
+        }else{
+            (None, &args[1.min(args.len())..])
+        };
+
+        if keyword == ".irp"{
+            return (Some(symbol), counter, values.to_vec());
+        }
+
+        // every character of `.irpc` string is used as its code, like 'c'
+        let string = match values{
+            [a] if a.ttype == LexemType::String => a,
+            _ => {
+                println!("{}:{}:{} .irpc expects symbol and one string", name.filename, name.row, name.col);
+                std::process::exit(1);
+            }
+        };
+
+        let values = string.value.chars().map(|a| Lexem::new((a as u32).to_string(), LexemType::Number { radix: 10 }, string.row, string.col, string.filename.clone())).collect();
+
+        (Some(symbol), counter, values)
+    }
+
+    // conditional blocks are resolved and repetitions unrolled before labels are discovered, tokens in skipped blocks are dropped.
+    // Macro bodies are left alone, directives inside them are resolved after expansion
+    fn resolve_block_directives(self: &Self, tokens: &[Token]) -> Vec<Token>{
+        let mut new_tokens: Vec<Token> = Vec::new();
+        let mut blocks: Vec<Conditional> = Vec::new();
+
+        let mut symbols: HashMap<String, i64> = HashMap::new();
+        let mut defined: HashSet<String> = HashSet::new();
+        let mut last_label = String::new();
+        let mut in_macro = false;
+
+        // included files that are still open, main file is root
+        let mut root: Option<(PathBuf, String)> = None;
+        let mut includes: Vec<(PathBuf, String)> = Vec::new();
+
+        // unrolled repetitions and included files are put back in front of remaining tokens,
+        // every token knows how many includes deep it is
+        let mut pending: Vec<(Token, usize)> = tokens.iter().rev().map(|a| (a.clone(), 0)).collect();
+
+        while let Some((token, include_depth)) = pending.pop(){
+            includes.truncate(include_depth);
+
+            let active = blocks.last().is_none_or(|a| a.active);
+
+            // macro body is kept as is until the macro is expanded
+            if in_macro{
+                in_macro = !matches!(&token, Token::Instruction { name, .. } if name.value.to_lowercase() == ".endm");
+                new_tokens.push(token);
+                continue;
+            }
+
+            let (name, args) = match &token{
+                Token::Label { name } => {
+                    if active{
+                        if name.value.starts_with("."){
+                            defined.insert(last_label.clone() + name.value.as_str());
+                        }else if is_local_label(&name.value) || name.value.contains('@'){
+                            defined.insert(name.value.clone());
+                        }else{
+                            last_label = name.value.clone();
+                            defined.insert(last_label.clone());
+                        }
+                        new_tokens.push(token.clone());
+                    }
+                    continue;
+                }
+                Token::Instruction { name, args } => (name, args)
+            };
+
+            let keyword = name.value.to_lowercase();
+
+            match keyword.as_str(){
+                ".if" | ".ifdef" | ".ifndef" => {
+                    let condition = active && Self::condition(name, args, &symbols, &defined, &last_label);
+                    blocks.push(Conditional { start: name.clone(), parent_active: active, taken: condition, active: condition, seen_else: false });
+                }
+
+                ".elif" | ".else" => {
+                    let block = match blocks.last_mut(){
+                        Some(a) if !a.seen_else => a,
+                        Some(_) => {
+                            println!("{}:{}:{} {} after .else", name.filename, name.row, name.col, name.value);
+                            std::process::exit(1);
+                        }
+                        None => {
+                            println!("{}:{}:{} {} without .if", name.filename, name.row, name.col, name.value);
+                            std::process::exit(1);
+                        }
+                    };
+
+                    // condition isn't evaluated when earlier branch was taken
+                    block.active = block.parent_active && !block.taken && (keyword == ".else" || Self::condition(name, args, &symbols, &defined, &last_label));
+                    block.taken |= block.active;
+                    block.seen_else = keyword == ".else";
+                }
+
+                ".endif" => {
+                    if blocks.pop().is_none(){
+                        println!("{}:{}:{} .endif without .if", name.filename, name.row, name.col);
+                        std::process::exit(1);
+                    }
+                }
+
+                _ if !active => {}
+
+                ".macro" => {
+                    in_macro = true;
+                    new_tokens.push(token.clone());
+                }
+
+                // file is only read when its block is assembled
+                ".include" => {
+                    let file = match args.as_slice(){
+                        [a] if a.ttype == LexemType::String => a,
+                        _ => {
+                            println!("{}:{}:{} Expected file name in quotes after .include", name.filename, name.row, name.col+name.value.len());
+                            std::process::exit(1);
+                        }
+                    };
+
+                    let path = Lexer::find_include(&name.filename, file, &self.include_dirs);
+                    let canonical = std::fs::canonicalize(&path).unwrap();
+
+                    if include_depth == 0{
+                        root = std::fs::canonicalize(&name.filename).ok().map(|a| (a, name.filename.clone()));
+                    }
+
+                    let open: Vec<&(PathBuf, String)> = root.iter().chain(includes.iter()).collect();
+
+                    if open.iter().any(|(a, _)| *a == canonical){
+                        let mut chain: Vec<String> = open.iter().map(|(_, a)| a.clone()).collect();
+                        chain.push(path);
+                        println!("{}:{}:{} Include cycle: {}", file.filename, file.row, file.col, chain.join(" -> "));
+                        std::process::exit(1);
+                    }
+
+                    let mut lexer: Lexer = Lexer::new();
+                    lexer.dollar_hex = self.dollar_hex;
+                    lexer.lex_file(&path, Some(file));
+
+                    let mut parser: Parser = Parser::new(None);
+                    parser.first_stage_parse(&lexer.lexems);
+
+                    includes.push((canonical, path));
+                    pending.extend(parser.tokens.into_iter().rev().map(|a| (a, includes.len())));
+                }
+
+                ".rept" | ".irp" | ".irpc" => {
+                    let (symbol, counter, values) = Self::repeat_header(name, args, &symbols, &last_label);
+
+                    let mut body: Vec<Token> = Vec::new();
+                    let mut depth = 0;
+
+                    loop{
+                        let token = match pending.pop(){
+                            Some((a, _)) => a,
+                            None => {
+                                println!("{}:{}:{} {} is missing .endr", name.filename, name.row, name.col, name.value);
+                                std::process::exit(1);
+                            }
+                        };
+
+                        if let Token::Instruction { name, .. } = &token{
+                            match name.value.to_lowercase().as_str(){
+                                ".rept" | ".irp" | ".irpc" => depth += 1,
+                                ".endr" if depth == 0 => break,
+                                ".endr" => depth -= 1,
+                                _ => {}
+                            }
+                        }
+
+                        body.push(token);
+                    }
+
+                    let mut unrolled: Vec<Token> = Vec::new();
+
+                    for (i, value) in values.iter().enumerate(){
+                        let mut iteration = body.clone();
+
+                        if let Some(counter) = &counter{
+                            let mut index = Lexem::new(i.to_string(), LexemType::Number { radix: 10 }, name.row, name.col, name.filename.clone());
+                            Self::replace_temp_arg_with_val(counter, &mut index, &mut iteration);
+                        }
+
+                        if let Some(symbol) = &symbol{
+                            Self::replace_temp_arg_with_val(symbol, &mut value.clone(), &mut iteration);
+                        }
+
+                        unrolled.append(&mut iteration);
+                    }
+
+                    pending.extend(unrolled.into_iter().rev().map(|a| (a, include_depth)));
+                }
+
+                ".endr" => {
+                    println!("{}:{}:{} .endr without .rept", name.filename, name.row, name.col);
+                    std::process::exit(1);
+                }
+
+                "equ" | "=" | ".set" => {
+                    if args.len() == 2 && args[0].ttype == LexemType::Ident{
+                        let symbol = fix_sub_label(&last_label, vec![args[0].clone()]).remove(0);
+                        let value = Self::fix_args(&symbols, &mut fix_sub_label(&last_label, vec![args[1].clone()])).remove(0);
+
+                        // constants that depend on labels are only known after label discovery
+                        if is_constant_expression(&value){
+                            symbols.insert(symbol.value.clone(), eval_operand(&value));
+                        }else{
+                            symbols.remove(&symbol.value);
+                        }
+                        defined.insert(symbol.value);
+                    }
+                    new_tokens.push(token.clone());
+                }
+
+                _ => new_tokens.push(token.clone())
+            }
+        }
+
+        if let Some(block) = blocks.last(){
+            println!("{}:{}:{} {} is missing .endif", block.start.filename, block.start.row, block.start.col, block.start.value);
+            std::process::exit(1);
+        }
+
+        new_tokens
+    }
+
+    // numeric local labels can be defined many times, every definition gets its own name `1@n`
+    fn rename_local_labels(tokens: &mut [Token]){
+        let mut total: HashMap<String, usize> = HashMap::new();
+
+        for token in tokens.iter(){
+            if let Token::Label { name } = token{
+                if is_local_label(&name.value){
+                    *total.entry(name.value.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut seen: HashMap<String, usize> = HashMap::new();
+
+        for token in tokens.iter_mut(){
+            match token{
+                Token::Label { name } => {
+                    if is_local_label(&name.value){
+                        let count = seen.entry(name.value.clone()).or_insert(0);
+                        name.value = format!("{}@{}", name.value, count);
+                        *count += 1;
+                    }
+                }
+                Token::Instruction { name: _, args } => {
+                    for arg in args.iter_mut(){
+                        rename_local_reference(arg, &seen, &total);
+                    }
+                }
+            }
+        }
+    }
+
+    // labels and constants defined inside `.scope name` / `.proc name` get qualified names `name.label`,
+    // `.proc` also defines label at its start. References are resolved after every scope is known
+    fn resolve_scopes(tokens: &Vec<Token>) -> Vec<Token>{
+        let mut scoped_tokens: Vec<(Token, Vec<String>)> = Vec::new();
+
+        let mut scopes: Vec<String> = Vec::new();
+        let mut openings: Vec<Lexem> = Vec::new();
+        let mut defined: HashSet<String> = HashSet::new();
+        let mut last_label = String::new();
+
+        let qualify = |scopes: &Vec<String>, name: &String| -> String{
+            match scopes.is_empty(){
+                true => name.clone(),
+                false => format!("{}.{}", scopes.join("."), name)
+            }
+        };
+
+        for token in tokens{
+            let mut token = token.clone();
+
+            match &mut token{
+                Token::Label { name } => {
+                    if name.value.starts_with("."){
+                        defined.insert(last_label.clone() + name.value.as_str());
+                    }else if !is_local_label(&name.value){
+                        name.value = qualify(&scopes, &name.value);
+                        defined.insert(name.value.clone());
+
+                        if !name.value.contains('@'){
+                            last_label = name.value.clone();
+                        }
+                    }
+                }
+
+                Token::Instruction { name, args } => {
+                    match name.value.to_lowercase().as_str(){
+                        ".scope" | ".proc" => {
+                            let scope = match args.as_slice(){
+                                [a] if a.ttype == LexemType::Ident && !a.value.contains('.') => a.clone(),
+                                _ => {
+                                    println!("{}:{}:{} {} expects name", name.filename, name.row, name.col, name.value);
+                                    std::process::exit(1);
+                                }
+                            };
+
+                            // `.proc name` is label `name` with scope of the same name
+                            if name.value.to_lowercase() == ".proc"{
+                                let label = Lexem::new(qualify(&scopes, &scope.value), LexemType::Ident, scope.row, scope.col, scope.filename.clone());
+                                defined.insert(label.value.clone());
+                                last_label = label.value.clone();
+                                scoped_tokens.push((Token::Label { name: label }, scopes.clone()));
+                            }
+
+                            scopes.push(scope.value);
+                            openings.push(name.clone());
+                            continue;
+                        }
+
+                        ".endscope" | ".endproc" => {
+                            let expected = match openings.last().map(|a| a.value.to_lowercase()).as_deref(){
+                                Some(".scope") => ".endscope",
+                                Some(_) => ".endproc",
+                                None => {
+                                    println!("{}:{}:{} {} without .scope or .proc", name.filename, name.row, name.col, name.value);
+                                    std::process::exit(1);
+                                }
+                            };
+
+                            if name.value.to_lowercase() != expected{
+                                let opening = openings.last().unwrap();
+                                println!("{}:{}:{} {} doesn't match {} at {}:{}", name.filename, name.row, name.col, name.value, opening.value, opening.row, opening.col);
+                                std::process::exit(1);
+                            }
+
+                            scopes.pop();
+                            openings.pop();
+                            continue;
+                        }
+
+                        "equ" | "=" | ".set" => {
+                            if let Some(symbol) = args.first_mut(){
+                                if symbol.ttype == LexemType::Ident && !symbol.value.starts_with("."){
+                                    symbol.value = qualify(&scopes, &symbol.value);
+                                    defined.insert(symbol.value.clone());
+                                }
+                            }
+                        }
+
+                        _ => {}
+                    }
+                }
+            }
+
+            scoped_tokens.push((token, scopes.clone()));
+        }
+
+        if let Some(opening) = openings.last(){
+            println!("{}:{}:{} {} {} is missing .end{}", opening.filename, opening.row, opening.col, opening.value, scopes.last().unwrap(), opening.value[1..].to_lowercase());
+            std::process::exit(1);
+        }
+
+        let mut new_tokens: Vec<Token> = Vec::new();
+
+        for (mut token, scopes) in scoped_tokens{
+            if let Token::Instruction { name: _, args } = &mut token{
+                for arg in args.iter_mut(){
+                    resolve_scoped(arg, &scopes, &defined);
+                }
+            }
+
+            new_tokens.push(token);
+        }
+
+        new_tokens
+    }
+
+    pub fn expand_pseudo_instructions(self: &mut Self, instruction_lexer: &InstructionsLexer){
         match self.pseudo_instructions.clone(){
-            Some(a) => self.tokens = Self::expand_block(&a, &self.tokens),
+            Some(mut a) => {
+                let macro_params = self.collect_macros(&mut a, instruction_lexer);
+                self.tokens = Self::expand_block(&a, &macro_params, &self.tokens, 0, &mut 0);
+            }
             None => unreachable!()
         }
     }
@@ -721,7 +1949,15 @@ impl Parser{
         
         self.first_stage_parse(lexems);
 
-        self.expand_pseudo_instructions();
+        self.tokens = self.resolve_block_directives(&self.tokens);
+
+        self.expand_pseudo_instructions(instruction_lexer);
+
+        self.tokens = self.resolve_block_directives(&self.tokens);
+
+        Self::rename_local_labels(&mut self.tokens);
+
+        self.tokens = Self::resolve_scopes(&self.tokens);
 
         self.calculate_labels(instruction_lexer);
 
@@ -1,27 +1,31 @@
 use std::collections::HashMap;
 
-use crate::{Token, Parser, Lexer, PSEUDO_INSTRUCTIONS};
+use crate::{Token, Parser, Lexer, Isa};
 
 #[derive(Debug)]
 pub struct PseudoInstructions{}
 
 impl PseudoInstructions{
-    pub fn initialize() -> HashMap<String, (Vec<String>,Vec<Token>)>{
+    pub fn initialize(isa: &Isa) -> HashMap<String, (Vec<String>,Vec<Token>)>{
         let mut pseudo_instructions_lexer: Lexer = Lexer::new();
         let mut pseudo_instructions_parser: Parser = Parser::new(None);
 
         let mut pseudo_instructions: HashMap<String, (Vec<String>,Vec<Token>)> = HashMap::new();
         
-        for (name, code) in PSEUDO_INSTRUCTIONS.entries(){
+        for entry in isa.pseudo_instructions.iter(){
             let mut p_args = Vec::new();
-            let name = *name;
-            let code = *code;
+            let name = entry.name.as_str();
+            let code = entry.body.as_str();
 
             let pure_name = name.split(" ").collect::<Vec<&str>>()[0];
 
-            let source_filename = "PSEUDO_INSTRUCTION_NAME_".to_string() + pure_name.to_uppercase().as_str();
+            if isa.is_builtin(){
+                let source_filename = "PSEUDO_INSTRUCTION_NAME_".to_string() + pure_name.to_uppercase().as_str();
 
-            pseudo_instructions_lexer.lex(&source_filename, name);
+                pseudo_instructions_lexer.lex(&source_filename, name);
+            }else{
+                pseudo_instructions_lexer.lex_at(&entry.filename, name, entry.row);
+            }
 
             pseudo_instructions_parser.first_stage_parse(&pseudo_instructions_lexer.lexems);
 
@@ -43,9 +47,13 @@ impl PseudoInstructions{
             }
 
 
-            let source_filename = "PSEUDO_INSTRUCTION_CODE_".to_string() + pure_name.to_uppercase().as_str();
+            if isa.is_builtin(){
+                let source_filename = "PSEUDO_INSTRUCTION_CODE_".to_string() + pure_name.to_uppercase().as_str();
 
-            pseudo_instructions_lexer.lex(source_filename.as_str(), code);
+                pseudo_instructions_lexer.lex(source_filename.as_str(), code);
+            }else{
+                pseudo_instructions_lexer.lex_at(&entry.filename, code, entry.row);
+            }
 
             pseudo_instructions_parser.first_stage_parse(&pseudo_instructions_lexer.lexems);
 
@@ -1,10 +1,18 @@
 use phf::phf_map;
 
 //format {(type)(count in bits)}
+// {(type)[hi:lo]} - only bits hi..lo of operand, slices of the same type make one operand
+// {(type)(count in bits)@n} - field belongs to n-th operand
 // possible types:
 // IMM - Immediate
+// SIMM - Signed immediate (two's complement)
+// REL - Signed offset from address of the instruction
 // E - Extra
 // + declared
+//
+// key is mnemonic optionally followed by operand template
+// where % is operand and [ ] : + are literal tokens
+// e.g. "st [seg:%]" => "{R4} 0 110"
 
 pub const TYPES: phf::Map<&'static str, phf::Map<&'static str, usize>> = phf_map!{
     "R" => REGISTERS_TO_VAL,
@@ -71,29 +79,42 @@ pub const INSTRUCTIONS: phf::Map<&'static str, &'static str> = phf_map!{
     "ld.f"   => "{R4}   1 101",
     
     "st"   => "{R4}   0 110",
+
+    "ld [%]"       => "{R4}   0 101",
+    "ld.f [%]"     => "{R4}   1 101",
+    "st [seg:%]"   => "{R4}   0 110",
+
     "b"    => "{C4}   0 111"
 };
 
-// Closure ops
-// +  add
-// -  subtract
+// Closure ops (from highest precedence)
+// -  negate (unary)
+// ~  bitwise not (unary)
+// !  logical not (unary)
 // *  multuply
 // /  divide
-// &  bitwise and
-// |  bitwise or
-// ^  bitwise xor
+// %  remainder
+// +  add
+// -  subtract
 // << bitshift left
 // >> bitshift right
+// <  <=  >  >=  comparisons (1 or 0)
+// == !=  equality (1 or 0)
+// &  bitwise and
+// ^  bitwise xor
+// |  bitwise or
+// && logical and
+// || logical or
+// ?: conditional
 
 pub const PSEUDO_INSTRUCTIONS: phf::Map<&'static str, &'static str> = phf_map!{
     "nop" => "b false",
     "lim imm" => "
         swa zero
-        addi (((imm >> 4)+(( imm & 8 ) >> 3)) & 0b00001111)
-        add acc
-        add acc
-        add acc
-        add acc
+        addi (((imm >> 4) + (imm & 8 ? 1 : 0)) & 0b00001111)
+        .rept 4
+            add acc
+        .endr
         addi (imm & 0b00001111)
     ",
     "lda src" => "
@@ -8,52 +8,192 @@
 
 use std::io::Write;
 use std::path::Path;
-use std::{fs::File, io::Read};
+use std::fs::File;
 
 mod components;
 mod config;
 use components::lexer::*;
 use components::parser::*;
 use components::codegen::*;
+use components::byteoperations::*;
 use config::*;
 use components::instruction_lexer::*;
 use components::pseudo_instructions::PseudoInstructions;
+use components::isa_validator::*;
+use components::isa_docs::*;
+use components::isa::*;
 
 fn main() {
-    let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
-
-    instruction_lexer.lex_instructions();
-
     let mut args = std::env::args();
 
     let filename = args.next().unwrap();
 
-    let source_filename = match args.next(){
-        Some(n) => {n},
+    let mut source_filename: Option<String> = None;
+    let mut isa_name: Option<String> = None;
+    let mut check_isa = false;
+    let mut dollar_hex = false;
+    let mut docs_dir: Option<String> = None;
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut defines: Vec<String> = Vec::new();
+
+    let mut isa_registry: IsaRegistry = IsaRegistry::new();
+
+    while let Some(arg) = args.next(){
+        match arg.as_str(){
+            "--isa-file" | "--isa-dir" | "--isa" | "--emit-isa-docs" | "-I" | "-D" => {
+                let value = match args.next(){
+                    Some(n) => n,
+                    None => {
+                        println!("{}: {} expects a value", filename, arg);
+                        std::process::exit(1);
+                    }
+                };
+
+                match arg.as_str(){
+                    "--isa-file" => isa_registry.add(Isa::load(Path::new(&value))),
+                    "--isa-dir" => isa_registry.load_dir(Path::new(&value)),
+                    "--emit-isa-docs" => docs_dir = Some(value),
+                    "-I" => include_dirs.push(value),
+                    "-D" => defines.push(value),
+                    _ => isa_name = Some(value)
+                }
+            }
+            "--check-isa" => check_isa = true,
+            "--dollar-hex" => dollar_hex = true,
+            _ if arg.starts_with("-I") => include_dirs.push(arg[2..].to_string()),
+            _ if arg.starts_with("-D") => defines.push(arg[2..].to_string()),
+            _ => {
+                if source_filename.is_some(){
+                    println!("{}: Unexpected argument {}", filename, arg);
+                    std::process::exit(1);
+                }
+                source_filename = Some(arg);
+            }
+        }
+    }
+
+    let mut lexer: Lexer = Lexer::new();
+    lexer.dollar_hex = dollar_hex;
+
+    // source is optional when only checking or documenting ISA
+    if source_filename.is_none() && !check_isa && docs_dir.is_none(){
+        println!("{}: Source Filename wasn't provided", filename);
+        std::process::exit(1);
+    }
+
+    if let Some(source_filename) = &source_filename{
+        lexer.lex_file(source_filename, None);
+    }
+
+    // `-D NAME=value` is the same as `NAME equ value` at the start of source, value defaults to 1
+    let mut define_lexems: Vec<Lexem> = Vec::new();
+
+    for define in defines.iter(){
+        let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+
+        if name.is_empty() || !name.chars().all(|a| a.is_alphanumeric() || a == '_') || name.starts_with(|a: char| a.is_ascii_digit()){
+            println!("{}: -D expects NAME[=value] got {}", filename, define);
+            std::process::exit(1);
+        }
+
+        let mut define_lexer: Lexer = Lexer::new();
+        define_lexer.dollar_hex = dollar_hex;
+        define_lexer.lex(&format!("-D {}", define), &format!("{} equ {}\n", name, value));
+        define_lexems.append(&mut define_lexer.lexems);
+    }
+
+    lexer.lexems.splice(0..0, define_lexems);
+
+    let cpu_directive = find_cpu_directive(&lexer.lexems);
+
+    if let Some(cpu) = cpu_directive.clone(){
+        match &isa_name{
+            Some(a) if a != &cpu.value => {
+                println!("{}:{}:{} Source selects ISA {} but {} was selected on command line", cpu.filename, cpu.row, cpu.col, cpu.value, a);
+                std::process::exit(1);
+            }
+            _ => isa_name = Some(cpu.value)
+        }
+    }
+
+    // without --isa or .cpu the only loaded ISA file is used instead of built-in one
+    let loaded: Vec<&Isa> = isa_registry.isas.iter().filter(|a| !a.is_builtin()).collect();
+
+    let isa_name = match (isa_name, loaded.as_slice()){
+        (Some(a), _) => a,
+        (None, [isa]) => isa.name.clone(),
+        (None, _) => "default".to_string()
+    };
+
+    let isa = match isa_registry.get(&isa_name){
+        Some(a) => a,
         None => {
-            println!("{}: Source Filename wasn't provided", filename);
+            match cpu_directive{
+                Some(cpu) => println!("{}:{}:{} Unknown ISA {} (available: {})", cpu.filename, cpu.row, cpu.col, isa_name, isa_registry.names().join(", ")),
+                None => println!("{}: Unknown ISA {} (available: {})", filename, isa_name, isa_registry.names().join(", "))
+            }
             std::process::exit(1);
         }
     };
 
-    let path = Path::new(&source_filename);
+    let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
 
-    let mut file = File::open(path).unwrap();
+    instruction_lexer.lex_instructions(isa);
 
-    let mut content = String::new();
+    let pseudo_instructions = PseudoInstructions::initialize(isa);
 
-    file.read_to_string(&mut content).unwrap();
+    let mut validator: IsaValidator = IsaValidator::new(&instruction_lexer, &pseudo_instructions);
 
-    
-    let mut lexer: Lexer = Lexer::new();
-    
-    lexer.lex(&source_filename, &content);
-    
-    let mut parser: Parser = Parser::new(Some(PseudoInstructions::initialize()));
+    validator.validate();
+
+    for diagnostic in validator.diagnostics.iter(){
+        println!("{}", diagnostic);
+    }
+
+    if check_isa{
+        if validator.has_errors(){
+            std::process::exit(1);
+        }
+        println!("ISA {} is valid ({} warnings)", isa.name, validator.diagnostics.len());
+        std::process::exit(0);
+    }
+
+    if validator.has_errors(){
+        std::process::exit(1);
+    }
+
+    if let Some(docs_dir) = docs_dir{
+        let docs = IsaDocs::new(isa, &instruction_lexer, &pseudo_instructions);
+
+        if let Err(e) = std::fs::create_dir_all(&docs_dir){
+            println!("{}: Couldn't create directory: {}", docs_dir, e);
+            std::process::exit(1);
+        }
+
+        for (extension, content) in [("md", docs.markdown()), ("html", docs.html())]{
+            let output_str = Path::new(&docs_dir).join(format!("{}.{}", isa.name, extension)).to_str().unwrap().to_string();
+
+            if let Err(e) = std::fs::write(&output_str, content){
+                println!("{}: Couldn't write ISA docs: {}", output_str, e);
+                std::process::exit(1);
+            }
+
+            println!("ISA docs: {}", output_str);
+        }
+
+        std::process::exit(0);
+    }
+
+    let source_filename = source_filename.unwrap();
+    let path = Path::new(&source_filename);
+
+    let mut parser: Parser = Parser::new(Some(pseudo_instructions));
+    parser.include_dirs = include_dirs;
+    parser.dollar_hex = dollar_hex;
     
     parser.parse(&lexer.lexems, &instruction_lexer);
     
-    let mut codegen: CodeGen = CodeGen::new(&parser.tokens, &instruction_lexer.instructions);
+    let mut codegen: CodeGen = CodeGen::new(&parser.tokens, &parser.forms, &instruction_lexer);
 
     codegen.gen();
 